@@ -0,0 +1,109 @@
+//! A job pool, it runs the commands read from stdin, one per line, in
+//! parallel. Every command holds a token of the job server while it
+//! runs, so the concurrency is bounded by what Make grants. Run it
+//! from a recipe with the `+` prefix, e.g. `+printf 'sleep 1\nsleep 1\n'
+//! | cargo run --example pool`.
+
+#![warn(rust_2018_idioms)]
+#![warn(clippy::pedantic)]
+
+#[cfg(target_family = "unix")]
+use std::{
+    io::{self, BufRead},
+    process::{Command, ExitStatus},
+    thread,
+};
+
+#[cfg(target_family = "unix")]
+use anyhow::Context;
+#[cfg(target_family = "unix")]
+use jobserver_demo_client_rs::Client;
+
+// Runs every command with `sh -c` on its own thread while holding a
+// token, returns the exit status of each in order.
+#[cfg(target_family = "unix")]
+fn run_pool(
+    client: &Client,
+    commands: impl IntoIterator<Item = String>,
+) -> anyhow::Result<Vec<(String, io::Result<ExitStatus>)>> {
+    thread::scope(|scope| {
+        let mut running = Vec::new();
+        for command in commands {
+            let token = client.acquire().context("acquiring token")?;
+            running.push(scope.spawn(move || {
+                let status = Command::new("sh").arg("-c").arg(&command).status();
+                // the process exited, the token is released
+                drop(token);
+                (command, status)
+            }));
+        }
+
+        Ok(running
+            .into_iter()
+            .map(|command| command.join().expect("the command thread doesn't panic"))
+            .collect())
+    })
+}
+
+#[cfg(target_family = "unix")]
+fn main() -> anyhow::Result<()> {
+    let client = Client::from_env()?.unwrap_or_else(Client::serial_fallback);
+
+    let commands = io::stdin()
+        .lock()
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .collect::<io::Result<Vec<_>>>()
+        .context("reading commands")?;
+
+    let mut failed = 0;
+    for (command, status) in run_pool(&client, commands)? {
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("{command}: {status}");
+                failed += 1;
+            }
+            Err(err) => {
+                eprintln!("{command}: {err}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} commands failed");
+    }
+
+    Ok(())
+}
+
+// the client of the library needs Unix
+#[cfg(not(target_family = "unix"))]
+fn main() -> anyhow::Result<()> {
+    anyhow::bail!("the pool only runs on Unix")
+}
+
+#[cfg(all(test, feature = "server", target_family = "unix"))]
+mod tests {
+    use std::{env, process};
+
+    use jobserver_demo_client_rs::{
+        available_token_estimate, parse_jobserver_auth, server::JobServer, Client,
+    };
+
+    #[test]
+    fn test_run_pool() {
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-pool", process::id()));
+        let job_server = JobServer::create_fifo(&path, 2).unwrap();
+        let makeflags = job_server.makeflags();
+        let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+        let client = Client::new(&style).unwrap();
+
+        let statuses = super::run_pool(&client, vec![String::from("true"); 5]).unwrap();
+        assert_eq!(statuses.len(), 5);
+        assert!(statuses
+            .iter()
+            .all(|(_, status)| status.as_ref().unwrap().success()));
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+    }
+}
@@ -0,0 +1,141 @@
+//! An async client for build tools in the smol ecosystem, it awaits
+//! readability of the job server with `async-io`.
+
+use std::{fs::File, io};
+
+use async_io::Async;
+
+use crate::{Client, JobServerStyle, Token};
+
+/// A connection to the job server which awaits tokens on the
+/// `async-io` reactor instead of blocking. The tokens are read and
+/// released by a [`Client`], so they are the same [`Token`]s as of the
+/// sync client.
+#[derive(Debug)]
+pub struct AsyncClient {
+    client: Client,
+    // awaits the job server becoming readable, the tokens are read by
+    // the client, None for the serial fallback
+    readable: Option<Async<File>>,
+}
+
+impl AsyncClient {
+    /// Connects to the job server described by `style` and registers
+    /// it with the reactor.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the job server cannot be opened or registered with the
+    /// reactor.
+    pub fn new(style: &JobServerStyle<'_>) -> io::Result<Self> {
+        Self::from_client(Client::new(style)?)
+    }
+
+    /// Awaits the tokens of `client`, e.g. one built with a
+    /// [`ClientBuilder`](crate::ClientBuilder) for its token size, or
+    /// the [`Client::serial_fallback`], which grants its implicit token.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the job server cannot be registered with the reactor.
+    pub fn from_client(client: Client) -> io::Result<Self> {
+        // only for readiness, nothing is read through it, so it's left
+        // blocking instead of switching the description Make shares
+        let readable = client
+            .read_handle()
+            .map(|read| read.try_clone().and_then(Async::new_nonblocking))
+            .transpose()?;
+        Ok(Self { client, readable })
+    }
+
+    /// Acquires a token, awaits until one is available, like
+    /// [`Client::try_acquire`] whenever the job server is readable.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails, with
+    /// [`JobServerError::Closed`](crate::JobServerError::Closed) if the
+    /// job server was closed.
+    pub async fn acquire(&self) -> io::Result<Token> {
+        let Some(readable) = &self.readable else {
+            return Ok(self.client.acquire_implicit().await);
+        };
+
+        loop {
+            readable.readable().await?;
+            // None if another client was faster
+            if let Some(token) = self.client.try_acquire()? {
+                return Ok(token);
+            }
+        }
+    }
+
+    /// Releases a token previously acquired from this job server, like
+    /// dropping it. It doesn't block, if the pipe is full the release
+    /// is queued, see [`Client::try_acquire`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::release`].
+    pub fn release(&self, token: Token) -> io::Result<()> {
+        self.client.release(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::fd::AsRawFd,
+    };
+
+    use super::AsyncClient;
+    use crate::{
+        test_util::{pipe, TestFifo},
+        ClientBuilder, JobServerError, JobServerStyle,
+    };
+
+    #[test]
+    fn test_acquire_release() {
+        let mut fifo = TestFifo::new("async-io", b"+");
+
+        smol::block_on(async {
+            let client = AsyncClient::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+            let token = client.acquire().await.unwrap();
+            assert_eq!(token.as_ref(), b"+");
+            client.release(token).unwrap();
+        });
+
+        let mut token = [0; 1];
+        fifo.file.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[test]
+    fn test_token_size_closed() {
+        // the write end belongs to a different pipe, so the read end
+        // sees EOF once its own writer is gone
+        let (file_read, mut file_write) = pipe();
+        let (_other_read, other_write) = pipe();
+        let client = ClientBuilder::new()
+            .token_size(2)
+            .build(&JobServerStyle::Pipe(
+                file_read.as_raw_fd(),
+                other_write.as_raw_fd(),
+            ))
+            .unwrap();
+        file_write.write_all(b"ab").unwrap();
+        drop(file_write);
+
+        smol::block_on(async {
+            let client = AsyncClient::from_client(client).unwrap();
+            let token = client.acquire().await.unwrap();
+            assert_eq!(token.as_ref(), b"ab");
+            // released to the other pipe
+            drop(token);
+
+            let err = client.acquire().await.unwrap_err();
+            assert_eq!(JobServerError::from_io(&err), Some(&JobServerError::Closed));
+        });
+    }
+}
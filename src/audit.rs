@@ -0,0 +1,205 @@
+//! An audit trail of the token movements, e.g. to find out why a build
+//! only used a single core.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Receives every token acquired from and released to the job server
+/// by a [`Client`](crate::Client) configured with
+/// [`ClientBuilder::audit`](crate::ClientBuilder::audit). Tokens are
+/// released from any thread, so the sink has to be thread-safe.
+pub trait TokenAudit: fmt::Debug + Send + Sync {
+    /// A token was acquired, the implicit token is empty.
+    fn on_acquire(&self, token: &[u8]);
+
+    /// A token was released.
+    fn on_release(&self, token: &[u8]);
+}
+
+/// Appends every token event as a line of JSON to a file, e.g.
+/// `{"event":"acquire","token":[43],"time_ms":1718000000000}`.
+#[derive(Debug)]
+pub struct JsonlAudit {
+    file: Mutex<File>,
+}
+
+impl JsonlAudit {
+    /// Appends to the file at `path`, it's created if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be opened.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, event: &str, token: &[u8]) {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis());
+        let bytes: Vec<_> = token.iter().map(u8::to_string).collect();
+        let line = format!(
+            "{{\"event\":\"{event}\",\"token\":[{}],\"time_ms\":{time_ms}}}\n",
+            bytes.join(",")
+        );
+
+        append_line(&self.file, &line);
+    }
+}
+
+impl TokenAudit for JsonlAudit {
+    fn on_acquire(&self, token: &[u8]) {
+        self.record("acquire", token);
+    }
+
+    fn on_release(&self, token: &[u8]) {
+        self.record("release", token);
+    }
+}
+
+/// Appends every token event as a line of text to a file, e.g.
+/// `ACQUIRE 1718000000.123456 +`, with the time in seconds since the
+/// Unix epoch and the token bytes escaped like in a byte string.
+///
+/// Each process should trace to a file of its own, but as every line
+/// is appended with a single write, processes sharing a file don't
+/// garble each other's lines either.
+#[derive(Debug)]
+pub struct TraceAudit {
+    file: Mutex<File>,
+}
+
+impl TraceAudit {
+    /// Appends to the file at `path`, it's created if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be opened.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, event: &str, token: &[u8]) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{event} {}.{:06} {}\n",
+            time.as_secs(),
+            time.subsec_micros(),
+            token.escape_ascii()
+        );
+
+        append_line(&self.file, &line);
+    }
+}
+
+impl TokenAudit for TraceAudit {
+    fn on_acquire(&self, token: &[u8]) {
+        self.record("ACQUIRE", token);
+    }
+
+    fn on_release(&self, token: &[u8]) {
+        self.record("RELEASE", token);
+    }
+}
+
+// A whole line per write to a file opened for appending, so concurrent
+// events, also of other processes, don't interleave.
+fn append_line(file: &Mutex<File>, line: &str) {
+    let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(err) = file.write_all(line.as_bytes()) {
+        tracing::warn!(%err, "writing the token audit failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::Write,
+        os::fd::AsRawFd,
+        process,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{JsonlAudit, TokenAudit, TraceAudit};
+    use crate::{test_util::pipe, Client, JobServerStyle};
+
+    #[derive(Debug, Default)]
+    struct VecAudit(Mutex<Vec<(&'static str, Vec<u8>)>>);
+
+    impl TokenAudit for Arc<VecAudit> {
+        fn on_acquire(&self, token: &[u8]) {
+            self.0.lock().unwrap().push(("acquire", token.to_vec()));
+        }
+
+        fn on_release(&self, token: &[u8]) {
+            self.0.lock().unwrap().push(("release", token.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_audit_acquire_release() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"+").unwrap();
+        let audit = Arc::new(VecAudit::default());
+        let client = Client::builder()
+            .audit(Arc::clone(&audit))
+            .build(&style)
+            .unwrap();
+
+        client.acquire().unwrap().release().unwrap();
+        assert_eq!(
+            *audit.0.lock().unwrap(),
+            vec![("acquire", b"+".to_vec()), ("release", b"+".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_jsonl_audit() {
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-audit.jsonl", process::id()));
+        let audit = JsonlAudit::create(&path).unwrap();
+        audit.on_acquire(b"+");
+        audit.on_release(b"+");
+        let lines = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"event":"acquire","token":[43],"time_ms":"#));
+        assert!(lines[1].starts_with(r#"{"event":"release","token":[43],"time_ms":"#));
+    }
+
+    #[test]
+    fn test_trace_audit() {
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-audit.trace", process::id()));
+        let audit = TraceAudit::create(&path).unwrap();
+        audit.on_acquire(b"+");
+        audit.on_release(b"\0");
+        let lines = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<Vec<_>> = lines
+            .lines()
+            .map(|line| line.split(' ').collect())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0][0], lines[0][2]), ("ACQUIRE", "+"));
+        assert_eq!((lines[1][0], lines[1][2]), ("RELEASE", "\\x00"));
+        assert!(lines[0][1].parse::<f64>().is_ok());
+    }
+}
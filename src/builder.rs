@@ -0,0 +1,616 @@
+//! Configuration of the [`Client`], so options don't turn into ever
+//! more constructor variants.
+
+use std::{
+    borrow::Cow,
+    env,
+    ffi::{OsStr, OsString},
+    fs, io,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    jobserver_disabled, jobserver_feature, parse_job_count, parse_jobserver_auth_bytes, Client,
+    JobServerStyle, JobServerStyleOwned, JobserverProtocol, ParseJobserverAuthError, TokenAudit,
+    MAKE_FEATURES_VAR,
+};
+
+/// How [`Client::acquire_timeout`] waits for a token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Waits for the job server to become readable with `poll(2)`.
+    #[default]
+    Poll,
+    /// Retries with exponentially increasing sleeps, see
+    /// [`Client::acquire_backoff`].
+    Backoff,
+}
+
+/// What [`Client::acquire_with_policy`] does if no token is available.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AcquirePolicy {
+    /// Blocks until a token is available, like Make does, see
+    /// [`Client::acquire`].
+    #[default]
+    Block,
+    /// Fails right away, see [`Client::try_acquire`].
+    FailFast,
+    /// Waits at most for the duration, see [`Client::acquire_timeout`].
+    Timeout(Duration),
+}
+
+/// Builds a [`Client`] with non-default options.
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    timeout: Option<Duration>,
+    strategy: WaitStrategy,
+    policy: AcquirePolicy,
+    implicit_token: bool,
+    token_size: usize,
+    canonicalize_fifo: bool,
+    validate_release: bool,
+    audit: Option<Arc<dyn TokenAudit>>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            strategy: WaitStrategy::default(),
+            policy: AcquirePolicy::default(),
+            implicit_token: false,
+            token_size: 1,
+            canonicalize_fifo: false,
+            validate_release: false,
+            audit: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Creates a builder with the default options.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The timeout of [`Client::acquire_timeout`], without a timeout it
+    /// blocks until a token is available.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How [`Client::acquire_timeout`] waits for a token.
+    #[must_use]
+    pub fn strategy(mut self, strategy: WaitStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// What [`Client::acquire_with_policy`] does if no token is
+    /// available, blocking by default.
+    #[must_use]
+    pub fn policy(mut self, policy: AcquirePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether [`Client::acquire_tokens`] counts the implicit token
+    /// Make grants every job. It's never read from the job server, so
+    /// a client doing its own parallelism must count it to not
+    /// oversubscribe.
+    #[must_use]
+    pub fn implicit_token(mut self, implicit_token: bool) -> Self {
+        self.implicit_token = implicit_token;
+        self
+    }
+
+    /// The number of bytes per token, GNU Make uses one byte per token
+    /// and that's the default. That's the same for both styles, the
+    /// FIFO of Make 4.4 is merely a named variant of the pipe and
+    /// `jobserver_acquire` reads a single byte from either. Other job
+    /// servers may encode tokens in more bytes, they are read and
+    /// written back verbatim.
+    #[must_use]
+    pub fn token_size(mut self, token_size: usize) -> Self {
+        self.token_size = token_size;
+        self
+    }
+
+    /// Whether the symlinks and `..` components of the FIFO path are
+    /// resolved before it's opened, once at build time, so the path
+    /// advertised to children stays the same FIFO if a symlink is
+    /// changed later. Off by default,
+    /// the path is opened as Make advertised it. Only absolute paths
+    /// are canonicalized, a relative one is rejected either way, like
+    /// [`parse_jobserver_auth`](crate::parse_jobserver_auth) does.
+    #[must_use]
+    pub fn canonicalize_fifo(mut self, canonicalize_fifo: bool) -> Self {
+        self.canonicalize_fifo = canonicalize_fifo;
+        self
+    }
+
+    /// Whether [`Client::release`] rejects a token acquired from
+    /// another client, e.g. one of the old connection before
+    /// [`Client::reconnect`]. Off by default, a token is always
+    /// released to the job server it was acquired from.
+    #[must_use]
+    pub fn validate_release(mut self, validate_release: bool) -> Self {
+        self.validate_release = validate_release;
+        self
+    }
+
+    /// Records every token acquired and released in `audit`, e.g. a
+    /// [`JsonlAudit`](crate::JsonlAudit).
+    #[must_use]
+    pub fn audit(mut self, audit: impl TokenAudit + 'static) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// Connects to the job server described by `style`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the token size is zero, the FIFO path is relative or
+    /// cannot be canonicalized or like [`Client::new`].
+    pub fn build(self, style: &JobServerStyle<'_>) -> io::Result<Client> {
+        self.build_owned(&style.into())
+    }
+
+    /// Connects to the job server described by the owned `style`, e.g.
+    /// from [`parse_jobserver_auth_bytes`](crate::parse_jobserver_auth_bytes).
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::build`].
+    pub fn build_owned(self, style: &JobServerStyleOwned) -> io::Result<Client> {
+        if self.token_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "token size must not be zero",
+            ));
+        }
+
+        let client = match style {
+            JobServerStyleOwned::Fifo(fifo_file) if !fifo_file.is_absolute() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    ParseJobserverAuthError::RelativeFifoPath(fifo_file.display().to_string()),
+                ));
+            }
+            JobServerStyleOwned::Fifo(fifo_file) if self.canonicalize_fifo => {
+                let path = fs::canonicalize(fifo_file).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "canonicalizing jobserver fifo {}: {err}",
+                            fifo_file.display()
+                        ),
+                    )
+                })?;
+                Client::connect(&JobServerStyleOwned::Fifo(path))?
+            }
+            _ => Client::connect(style)?,
+        };
+        Ok(self.configure(client))
+    }
+
+    fn configure(self, mut client: Client) -> Client {
+        client.builder = self.clone();
+        client.timeout = self.timeout;
+        client.strategy = self.strategy;
+        client.policy = self.policy;
+        client.implicit_token = self.implicit_token;
+        client.token_size = self.token_size;
+        client.validate_release = self.validate_release;
+        client.set_audit(self.audit);
+        client
+    }
+
+    /// Connects to the job server advertised in `makeflags`, returns
+    /// `None` if there's none. With `-j1` the job server grants no
+    /// tokens besides the implicit one, so it isn't used at all and the
+    /// client is the [`Client::serial_fallback`]. Without any `-jN`,
+    /// e.g. behind a wrapper which dropped it, the job server is used
+    /// all the same, it tells how many tokens there are.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the job server auth is malformed or connecting fails.
+    pub fn from_makeflags(self, makeflags: &str) -> anyhow::Result<Option<Client>> {
+        self.from_makeflags_os(OsStr::new(makeflags))
+    }
+
+    /// Connects to the job server advertised in `makeflags` like
+    /// [`ClientBuilder::from_makeflags`], but from the raw bytes of the
+    /// environment, so a FIFO path that isn't UTF-8 is kept verbatim.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_makeflags`].
+    pub fn from_makeflags_os(self, makeflags: &OsStr) -> anyhow::Result<Option<Client>> {
+        // like parse_jobserver, but owned, so an escaped FIFO path
+        // is unescaped
+        let bytes = makeflags.as_encoded_bytes();
+        if bytes.trim_ascii().is_empty() {
+            return Ok(None);
+        }
+        let style = parse_jobserver_auth_bytes(bytes).context("parsing jobserver auth")?;
+
+        // the flags are ASCII, a lossy path doesn't change them
+        let job_count = parse_job_count(&makeflags.to_string_lossy());
+        if job_count == Some(1) {
+            tracing::info!("jobserver present, but parallelism is off (-j1), running serially");
+            let mut client = self.configure(Client::serial_fallback());
+            client.job_count = job_count;
+            return Ok(Some(client));
+        }
+
+        let mut client = self
+            .build_owned(&style)
+            .context("connecting to jobserver")?;
+        client.job_count = job_count;
+        Ok(Some(client))
+    }
+
+    /// Connects to the job server advertised in the `MAKEFLAGS`
+    /// environment variable, returns `None` if there's none or it's
+    /// disabled with [`JOBSERVER_DISABLE_VAR`](crate::JOBSERVER_DISABLE_VAR).
+    ///
+    /// If Make exports its features in [`MAKE_FEATURES_VAR`] and
+    /// doesn't support the FIFO, the pipe auth is preferred, like with
+    /// `--jobserver-style=pipe`. Without it `MAKEFLAGS` alone decides.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_makeflags`].
+    pub fn from_env(self) -> anyhow::Result<Option<Client>> {
+        self.from_env_var("MAKEFLAGS")
+    }
+
+    /// Connects to the job server like [`ClientBuilder::from_env`], but
+    /// advertised in the environment variable `name` instead of
+    /// `MAKEFLAGS`, e.g. `MFLAGS` or the variable of a wrapper tool.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_makeflags`].
+    pub fn from_env_var(self, name: &str) -> anyhow::Result<Option<Client>> {
+        if jobserver_disabled() {
+            tracing::info!("jobserver explicitly disabled, running serially");
+            return Ok(None);
+        }
+
+        let makeflags = env::var_os(name).unwrap_or_default();
+        match env::var_os(MAKE_FEATURES_VAR) {
+            Some(features) => {
+                self.from_makeflags_os(&with_feature_hint(&makeflags, &features.to_string_lossy()))
+            }
+            None => self.from_makeflags_os(&makeflags),
+        }
+    }
+}
+
+// Adds the style hint matching the features of Make to `makeflags`,
+// unless there's one already. Only a Make without FIFO support needs
+// one, a FIFO auth was leaked by another Make then, otherwise the last
+// auth wins.
+fn with_feature_hint<'a>(makeflags: &'a OsStr, features: &str) -> Cow<'a, OsStr> {
+    let bytes = makeflags.as_encoded_bytes();
+    if jobserver_feature(features) == Some(JobserverProtocol::Pipe)
+        && !bytes
+            .windows(b"--jobserver-style=".len())
+            .any(|window| window == b"--jobserver-style=")
+        && !bytes.trim_ascii().is_empty()
+    {
+        let mut hinted = OsString::from(makeflags);
+        hinted.push(" --jobserver-style=pipe");
+        Cow::Owned(hinted)
+    } else {
+        Cow::Borrowed(makeflags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        ffi::{OsStr, OsString},
+        fs,
+        io::{self, Write},
+        os::{
+            fd::AsRawFd,
+            unix::ffi::{OsStrExt, OsStringExt},
+        },
+        path::PathBuf,
+        process,
+        time::{Duration, Instant},
+    };
+
+    use super::{with_feature_hint, AcquirePolicy, ClientBuilder, WaitStrategy};
+    use crate::{
+        test_util::{mkfifo, pipe, TestFifo},
+        JobServerStyle, JobServerStyleOwned, JsonlAudit, JOBSERVER_DISABLE_VAR, MAKE_FEATURES_VAR,
+    };
+
+    #[test]
+    fn test_builder_from_env_var() {
+        let name = "JOBSERVER_DEMO_TEST_FLAGS";
+        if env::var_os(name).is_some() {
+            // the child process below
+            let client = ClientBuilder::new().from_env_var(name).unwrap().unwrap();
+            assert_eq!(client.acquire().unwrap().as_ref(), b"+");
+            return;
+        }
+
+        // the variable is only set for a child running this test, the
+        // other tests running in parallel share the environment
+        let fifo = TestFifo::new("from-env-var", b"+");
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "builder::tests::test_builder_from_env_var"])
+            .env(name, format!(" -j2 --jobserver-auth=fifo:{}", fifo.path()))
+            .env_remove(JOBSERVER_DISABLE_VAR)
+            .env_remove(MAKE_FEATURES_VAR)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+
+        assert!(ClientBuilder::new()
+            .from_env_var("JOBSERVER_DEMO_TEST_UNSET_FLAGS")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_builder_options() {
+        let (file_read, mut file_write) = pipe();
+        let makeflags = format!(
+            " -j3 --jobserver-auth={},{}",
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd()
+        );
+        let audit_path =
+            env::temp_dir().join(format!("jobserver-demo-{}-builder.jsonl", process::id()));
+        let audit = JsonlAudit::create(&audit_path).unwrap();
+        let client = ClientBuilder::new()
+            .token_size(2)
+            .policy(AcquirePolicy::FailFast)
+            .validate_release(true)
+            .audit(audit)
+            .from_makeflags(&makeflags)
+            .unwrap()
+            .unwrap();
+
+        assert!(client.acquire_with_policy().unwrap().is_none());
+        file_write.write_all(b"ab").unwrap();
+        let token = client.acquire_with_policy().unwrap().unwrap();
+        assert_eq!(token.bytes(), b"ab");
+        client.release(token).unwrap();
+
+        let other = ClientBuilder::new()
+            .token_size(2)
+            .build(&JobServerStyle::Pipe(
+                file_read.as_raw_fd(),
+                file_write.as_raw_fd(),
+            ))
+            .unwrap();
+        let err = client.release(other.acquire().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // released by its own client nonetheless
+        assert_eq!(client.acquire().unwrap().bytes(), b"ab");
+
+        let audit = fs::read_to_string(&audit_path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+        assert_eq!(audit.lines().count(), 4);
+    }
+
+    #[test]
+    fn test_with_feature_hint() {
+        let hint = |makeflags: &str, features: &str| {
+            with_feature_hint(OsStr::new(makeflags), features).into_owned()
+        };
+        let makeflags = " -j2 --jobserver-auth=fifo:/tmp/GMfifo1 --jobserver-auth=3,4";
+        assert_eq!(
+            hint(makeflags, "archives jobserver load"),
+            OsStr::new(&format!("{makeflags} --jobserver-style=pipe"))
+        );
+        assert_eq!(
+            hint(makeflags, "archives jobserver jobserver-fifo load"),
+            OsStr::new(makeflags)
+        );
+        assert_eq!(hint(makeflags, ""), OsStr::new(makeflags));
+        assert_eq!(
+            hint(" --jobserver-style=fifo", "jobserver"),
+            OsStr::new(" --jobserver-style=fifo")
+        );
+        assert_eq!(hint(" ", "jobserver"), OsStr::new(" "));
+    }
+
+    #[test]
+    fn test_builder_from_makeflags_os() {
+        // Make passes the fifo path through, UTF-8 or not
+        let fifo = mkfifo("non-utf8");
+        let mut path = fifo.clone().into_os_string().into_vec();
+        path.extend_from_slice(b"-\xff");
+        let path = PathBuf::from(OsString::from_vec(path));
+        fs::rename(&fifo, &path).unwrap();
+        let mut file = fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"+").unwrap();
+
+        let mut makeflags = b" -j2 --jobserver-auth=fifo:".to_vec();
+        makeflags.extend_from_slice(path.as_os_str().as_bytes());
+        let client = ClientBuilder::new()
+            .from_makeflags_os(OsStr::from_bytes(&makeflags))
+            .unwrap()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(client.style(), Some(&JobServerStyleOwned::Fifo(path)));
+        assert_eq!(client.parallelism(), Some(2));
+        assert_eq!(client.acquire().unwrap().as_ref(), b"+");
+    }
+
+    #[test]
+    fn test_builder_timeout() {
+        let (file_read, file_write) = pipe();
+        let makeflags = format!(
+            " -j2 --jobserver-auth={},{}",
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd()
+        );
+
+        for strategy in [WaitStrategy::Poll, WaitStrategy::Backoff] {
+            let client = ClientBuilder::new()
+                .timeout(Duration::from_millis(20))
+                .strategy(strategy)
+                .from_makeflags(&makeflags)
+                .unwrap()
+                .unwrap();
+            assert_eq!(client.timeout, Some(Duration::from_millis(20)));
+            assert_eq!(client.strategy, strategy);
+
+            let start = Instant::now();
+            assert!(client.acquire_timeout().unwrap().is_none());
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_builder_no_jobserver() {
+        assert!(ClientBuilder::new().from_makeflags("").unwrap().is_none());
+        assert!(ClientBuilder::new().from_makeflags(" -j2").is_err());
+    }
+
+    #[test]
+    fn test_builder_token_size_fifo() {
+        // like Make 4.4, a single byte per token and no more is read
+        let fifo = TestFifo::new("token-size-1", b"++");
+        let client = ClientBuilder::new()
+            .build(&JobServerStyle::Fifo(fifo.path()))
+            .unwrap();
+        let first = client.acquire().unwrap();
+        let second = client.acquire().unwrap();
+        assert_eq!(first.as_ref(), b"+");
+        assert_eq!(second.as_ref(), b"+");
+        assert!(client.try_acquire().unwrap().is_none());
+
+        let fifo = TestFifo::new("token-size-2", b"++");
+        let client = ClientBuilder::new()
+            .token_size(2)
+            .build(&JobServerStyle::Fifo(fifo.path()))
+            .unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"++");
+        assert!(client.try_acquire().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_builder_without_parallelism() {
+        // the fifo doesn't exist, it's never opened
+        let client = ClientBuilder::new()
+            .from_makeflags(" -j1 --jobserver-auth=fifo:/x")
+            .unwrap()
+            .unwrap();
+        assert!(client.is_serial());
+        assert!(client.acquire().unwrap().as_ref().is_empty());
+    }
+
+    #[test]
+    fn test_builder_token_size() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        assert!(ClientBuilder::new().token_size(0).build(&style).is_err());
+
+        // the default, one byte per token like GNU Make
+        let client = ClientBuilder::new().build(&style).unwrap();
+        file_write.write_all(b"+").unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        client.release(token).unwrap();
+        // held, so it's not released into the 4-byte tokens
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+
+        // a fabricated job server with 4-byte tokens
+        let client = ClientBuilder::new().token_size(4).build(&style).unwrap();
+        file_write.write_all(b"tok1tok2").unwrap();
+        let first = client.acquire().unwrap();
+        assert_eq!(first.as_ref(), b"tok1");
+        let second = client.try_acquire().unwrap().unwrap();
+        assert_eq!(second.as_ref(), b"tok2");
+        assert!(client.try_acquire().unwrap().is_none());
+        client.release(first).unwrap();
+        assert_eq!(client.acquire().unwrap().as_ref(), b"tok1");
+    }
+
+    #[test]
+    fn test_builder_relative_fifo() {
+        // rejected like in MAKEFLAGS, also when canonicalizing
+        for canonicalize_fifo in [false, true] {
+            let err = ClientBuilder::new()
+                .canonicalize_fifo(canonicalize_fifo)
+                .build(&JobServerStyle::Fifo("relative/GMfifo1"))
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(
+                err.to_string(),
+                "fifo path \"relative/GMfifo1\" is not absolute"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_canonicalize_fifo() {
+        // an absolute path with a detour, no change of the current
+        // directory, which other tests running in parallel would see
+        let fifo = TestFifo::new("canonicalize", b"+");
+        let dir = fifo.path.parent().unwrap();
+        let detour = dir
+            .join("..")
+            .join(dir.file_name().unwrap())
+            .join(fifo.path.file_name().unwrap());
+        let client = ClientBuilder::new()
+            .canonicalize_fifo(true)
+            .build_owned(&JobServerStyleOwned::Fifo(detour))
+            .unwrap();
+        assert_eq!(
+            client.style(),
+            Some(&JobServerStyleOwned::Fifo(
+                fs::canonicalize(&fifo.path).unwrap()
+            ))
+        );
+        assert_eq!(client.acquire().unwrap().as_ref(), b"+");
+
+        // a symlink to the fifo is resolved
+        let link = fifo.path.with_extension("link");
+        std::os::unix::fs::symlink(&fifo.path, &link).unwrap();
+        let client = ClientBuilder::new()
+            .canonicalize_fifo(true)
+            .build_owned(&JobServerStyleOwned::Fifo(link.clone()));
+        fs::remove_file(&link).unwrap();
+        assert_eq!(
+            client.unwrap().style(),
+            Some(&JobServerStyleOwned::Fifo(
+                fs::canonicalize(&fifo.path).unwrap()
+            ))
+        );
+
+        let err = ClientBuilder::new()
+            .canonicalize_fifo(true)
+            .build(&JobServerStyle::Fifo("/jobserver-demo-missing/../fifo"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("canonicalizing jobserver fifo"));
+    }
+}
@@ -0,0 +1,858 @@
+//! The command line of the demo client, it needs the Unix client of
+//! the library.
+
+use std::{
+    env,
+    ffi::OsStr,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use jobserver_demo_client_rs::{
+    classify_jobserver_protocol, fifo_pid, jobserver_disabled, parse_job_count,
+    parse_jobserver_auth_bytes, AcquirePolicy, Client, ClientBuilder, JobServerError,
+    JobServerStyleOwned, ParseJobserverAuthError, Token, TraceAudit, JOBSERVER_DISABLE_VAR,
+};
+
+/// The exit code of any failure without a code of its own. Running
+/// without a job server isn't a failure, it exits with 0.
+const EXIT_FAILURE: u8 = 1;
+/// The exit code if `MAKEFLAGS` advertises a malformed job server auth.
+const EXIT_MALFORMED_MAKEFLAGS: u8 = 2;
+/// The exit code if acquiring a token failed, or none was available
+/// with `--fail-fast` or `--timeout`.
+const EXIT_ACQUIRE_FAILED: u8 = 3;
+
+// Context of acquisition errors, it selects EXIT_ACQUIRE_FAILED.
+#[derive(Debug)]
+struct AcquireFailed;
+
+impl fmt::Display for AcquireFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("acquiring token")
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum Format {
+    /// The token as character, for humans.
+    #[default]
+    Text,
+    /// The style and token bytes as JSON, for scripts.
+    Json,
+}
+
+/// What to do instead of acquiring a token, the last one given wins.
+#[derive(Debug, Default, PartialEq)]
+enum Mode {
+    /// Acquires a token and prints it.
+    #[default]
+    Acquire,
+    /// Prints the detected job server protocol instead of connecting.
+    Protocol,
+    /// Prints a report on the job server connection instead of
+    /// acquiring a token.
+    Diagnose,
+    /// Checks whether the job server looks usable without acquiring a
+    /// token, e.g. as a preflight in CI.
+    Check,
+    /// Prints the job server `MAKEFLAGS` describes without connecting
+    /// to it.
+    DryRun,
+    /// Acquires and releases a token this many times and prints the
+    /// throughput, to benchmark the job server.
+    Bench(u32),
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    format: Format,
+    mode: Mode,
+    /// Runs this command while holding a token instead of printing it.
+    exec: Option<Vec<String>>,
+    /// What to do if no token is available.
+    policy: AcquirePolicy,
+    /// The environment variable with the job server auth, `MAKEFLAGS`
+    /// if unset.
+    makeflags_var: Option<String>,
+    /// A file with captured `MAKEFLAGS` to replay instead of the
+    /// environment. Its descriptors aren't ours, so it's never
+    /// connected to, acquiring is a dry run then.
+    makeflags_file: Option<PathBuf>,
+    /// Appends a line per token acquired and released to this file.
+    trace_file: Option<PathBuf>,
+    /// The job server given by `--fifo` or `--pipe`, e.g. of a build
+    /// system other than Make. It takes precedence over `MAKEFLAGS`,
+    /// which isn't parsed then.
+    style: Option<JobServerStyleOwned>,
+    /// Prints neither the token nor that a job server is present when
+    /// acquiring, e.g. within the rules of a Makefile, only warnings
+    /// and errors go to stderr.
+    quiet: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => {
+                    parsed.format = match args.next().as_deref() {
+                        Some("text") => Format::Text,
+                        Some("json") => Format::Json,
+                        Some(format) => anyhow::bail!("invalid format \"{format}\""),
+                        None => anyhow::bail!("missing value for --format"),
+                    };
+                }
+                "--protocol" => parsed.mode = Mode::Protocol,
+                "--diagnose" => parsed.mode = Mode::Diagnose,
+                "--check" => parsed.mode = Mode::Check,
+                "--dry-run" => parsed.mode = Mode::DryRun,
+                "--bench" => {
+                    let Some(n) = args.next() else {
+                        anyhow::bail!("missing value for --bench");
+                    };
+                    match n.parse() {
+                        Ok(n) if n > 0 => parsed.mode = Mode::Bench(n),
+                        _ => anyhow::bail!("invalid number of tokens \"{n}\""),
+                    }
+                }
+                "--quiet" => parsed.quiet = true,
+                "--makeflags-file" => match args.next() {
+                    Some(path) if !path.is_empty() => parsed.makeflags_file = Some(path.into()),
+                    _ => anyhow::bail!("missing value for --makeflags-file"),
+                },
+                "--makeflags-var" => match args.next() {
+                    Some(name) if !name.is_empty() => parsed.makeflags_var = Some(name),
+                    _ => anyhow::bail!("missing value for --makeflags-var"),
+                },
+                "--trace-file" => match args.next() {
+                    Some(path) if !path.is_empty() => parsed.trace_file = Some(path.into()),
+                    _ => anyhow::bail!("missing value for --trace-file"),
+                },
+                "--fifo" => match args.next() {
+                    // the same policy as for MAKEFLAGS
+                    Some(path) if !path.is_empty() && !Path::new(&path).is_absolute() => {
+                        return Err(ParseJobserverAuthError::RelativeFifoPath(path).into());
+                    }
+                    Some(path) if !path.is_empty() => {
+                        parsed.style = Some(JobServerStyleOwned::Fifo(path.into()));
+                    }
+                    _ => anyhow::bail!("missing value for --fifo"),
+                },
+                "--pipe" => {
+                    let Some(fds) = args.next() else {
+                        anyhow::bail!("missing value for --pipe");
+                    };
+                    let Some((Ok(read_fd), Ok(write_fd))) = fds
+                        .split_once(',')
+                        .map(|(read_fd, write_fd)| (read_fd.parse(), write_fd.parse()))
+                    else {
+                        anyhow::bail!("invalid pipe \"{fds}\", expected <read fd>,<write fd>");
+                    };
+                    parsed.style = Some(JobServerStyleOwned::Pipe(read_fd, write_fd));
+                }
+                "--wait-forever" => parsed.policy = AcquirePolicy::Block,
+                "--fail-fast" => parsed.policy = AcquirePolicy::FailFast,
+                "--timeout" => {
+                    let Some(millis) = args.next() else {
+                        anyhow::bail!("missing value for --timeout");
+                    };
+                    let millis = millis
+                        .parse()
+                        .with_context(|| format!("invalid timeout \"{millis}\""))?;
+                    parsed.policy = AcquirePolicy::Timeout(Duration::from_millis(millis));
+                }
+                "--exec" => {
+                    let command: Vec<_> = args.skip_while(|arg| arg == "--").collect();
+                    if command.is_empty() {
+                        anyhow::bail!("missing command for --exec");
+                    }
+                    parsed.exec = Some(command);
+                    break;
+                }
+                _ => anyhow::bail!("unknown argument \"{arg}\""),
+            }
+        }
+
+        if matches!(parsed.mode, Mode::Bench(_)) && parsed.exec.is_some() {
+            anyhow::bail!("--bench conflicts with --exec");
+        }
+        if parsed.style.is_some()
+            && (parsed.makeflags_file.is_some() || parsed.makeflags_var.is_some())
+        {
+            anyhow::bail!("--fifo and --pipe conflict with --makeflags-file and --makeflags-var");
+        }
+        if parsed.makeflags_file.is_some() {
+            if parsed.makeflags_var.is_some() {
+                anyhow::bail!("--makeflags-file conflicts with --makeflags-var");
+            }
+            if matches!(parsed.mode, Mode::Check | Mode::Bench(_)) || parsed.exec.is_some() {
+                anyhow::bail!("cannot connect to the jobserver of --makeflags-file");
+            }
+            if parsed.mode == Mode::Acquire {
+                parsed.mode = Mode::DryRun;
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn style_name(style: &JobServerStyleOwned) -> &'static str {
+    match style {
+        JobServerStyleOwned::Fifo(_) => "fifo",
+        JobServerStyleOwned::Pipe(_, _) => "pipe",
+    }
+}
+
+fn format_token(format: &Format, style: &JobServerStyleOwned, token: &[u8]) -> String {
+    match format {
+        Format::Text => token.iter().copied().map(char::from).collect(),
+        Format::Json => {
+            let bytes: Vec<_> = token.iter().map(u8::to_string).collect();
+            format!(
+                "{{\"style\":\"{}\",\"token\":[{}]}}",
+                style_name(style),
+                bytes.join(",")
+            )
+        }
+    }
+}
+
+// Prints a line of raw bytes, e.g. with a FIFO path which isn't UTF-8.
+fn println_os(line: &OsStr) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(line.as_bytes())?;
+    writeln!(stdout)
+}
+
+// Prints the report of --diagnose, a key and value per line.
+fn print_report(report: Vec<(&'static str, OsString)>) -> io::Result<()> {
+    for (key, value) in report {
+        let mut line = OsString::from(format!("{key}: "));
+        line.push(value);
+        println_os(&line)?;
+    }
+    Ok(())
+}
+
+// The report of --diagnose, a key and value per line. Tokens are only
+// probed, all of them are released again. With --dry-run it stops
+// before connecting.
+fn diagnose(makeflags_os: &OsStr, dry_run: bool) -> Vec<(&'static str, OsString)> {
+    let makeflags = makeflags_os.to_string_lossy();
+    if makeflags.trim().is_empty() {
+        return vec![("makeflags", "unset".into())];
+    }
+
+    let mut report = vec![
+        ("makeflags", "set".into()),
+        (
+            "protocol",
+            classify_jobserver_protocol(&makeflags)
+                .map_or_else(|| "none".into(), |protocol| protocol.to_string().into()),
+        ),
+    ];
+    let style = match parse_jobserver_auth_bytes(makeflags_os.as_bytes()) {
+        Ok(style) => style,
+        Err(err) => {
+            report.push(("style", format!("invalid ({err})").into()));
+            return report;
+        }
+    };
+    // the auth as in MAKEFLAGS, escaped and with the raw bytes
+    report.push(("style", style.to_auth()));
+    if let JobServerStyleOwned::Fifo(fifo_file) = &style {
+        report.push(("fifo pid", fifo_pid_report(fifo_file).into()));
+    }
+    report.push((
+        "parallelism",
+        parse_job_count(&makeflags).map_or_else(|| "unknown".into(), |n| n.to_string().into()),
+    ));
+    if !dry_run {
+        probe(&style, &mut report);
+    }
+
+    report
+}
+
+// The pid of the Make which created the FIFO, a name not matching
+// `GMfifo<pid>` is suspicious, MAKEFLAGS may be spoofed.
+fn fifo_pid_report(fifo_file: &Path) -> String {
+    if let Some(pid) = fifo_pid(fifo_file) {
+        return pid.to_string();
+    }
+    eprintln!(
+        "warning: jobserver fifo {} isn't named GMfifo<pid> like by Make",
+        fifo_file.display()
+    );
+    String::from("unknown")
+}
+
+// The part of the report of --diagnose which connects to the job
+// server, also for the one given by --fifo or --pipe.
+fn probe(style: &JobServerStyleOwned, report: &mut Vec<(&'static str, OsString)>) {
+    // the build checks that the fds are pipes or the path a FIFO
+    let client = match Client::builder().build_owned(style) {
+        Ok(client) => client,
+        Err(err) => {
+            report.push(("open", format!("failed ({err})").into()));
+            if let Some(JobServerError::NotAPipe(fd, fd_type)) = JobServerError::from_io(&err) {
+                report.push(("fd type", format!("{fd_type} (fd {fd})").into()));
+            }
+            return;
+        }
+    };
+    report.push(("open", "ok".into()));
+    report.push(("fd type", style_name(style).into()));
+
+    let mut tokens = Vec::new();
+    let available = loop {
+        match client.try_acquire() {
+            Ok(Some(token)) => tokens.push(token),
+            Ok(None) => break tokens.len().to_string(),
+            Err(err) => break format!("failed after {} ({err})", tokens.len()),
+        }
+    };
+    report.push(("tokens available", available.into()));
+    // released on drop
+    drop(tokens);
+}
+
+// Checks that the job server can be connected to and its fds are of
+// the right type, no token is read. Unlike connect it doesn't fall
+// back to serial execution, it fails instead.
+fn check(makeflags_os: &OsStr) -> anyhow::Result<OsString> {
+    if jobserver_disabled() {
+        anyhow::bail!("jobserver explicitly disabled by {JOBSERVER_DISABLE_VAR}");
+    }
+    if makeflags_os.to_string_lossy().trim().is_empty() {
+        anyhow::bail!("jobserver not available, MAKEFLAGS is empty");
+    }
+
+    let style =
+        parse_jobserver_auth_bytes(makeflags_os.as_bytes()).context("parsing jobserver auth")?;
+    check_style(&style)
+}
+
+// The part of check which connects, also for the job server given by
+// --fifo or --pipe.
+fn check_style(style: &JobServerStyleOwned) -> anyhow::Result<OsString> {
+    // the build fails with a JobServerError if an fd isn't a pipe
+    let _client = Client::builder()
+        .build_owned(style)
+        .context("connecting to jobserver")?;
+
+    let mut usable = OsString::from("jobserver usable: ");
+    usable.push(style.to_auth());
+    Ok(usable)
+}
+
+// Connects to the job server through the builder, like a library user
+// would, from the environment variable `var` or, for
+// --makeflags-file, from `makeflags_os`. If there's none or it cannot
+// be used, it falls back to serial execution with the implicit token
+// only. Only the warnings are printed here, the rules are the
+// library's.
+fn connect(
+    makeflags_os: &OsStr,
+    var: Option<&str>,
+    builder: ClientBuilder,
+    quiet: bool,
+) -> anyhow::Result<(Client, Option<JobServerStyleOwned>)> {
+    if jobserver_disabled() {
+        eprintln!(
+            "warning: jobserver explicitly disabled by {JOBSERVER_DISABLE_VAR}, running serially"
+        );
+        return Ok((Client::serial_fallback(), None));
+    }
+
+    let makeflags = makeflags_os.to_string_lossy();
+    // stdout only carries the token
+    if makeflags.contains("--jobserver-auth=") && !quiet {
+        eprintln!("jobserver present {makeflags}");
+    }
+
+    let connected = match var {
+        Some(var) => builder.from_env_var(var),
+        None => builder.from_makeflags_os(makeflags_os),
+    };
+    match connected {
+        Ok(None) => {
+            eprintln!("warning: jobserver not available");
+            Ok((Client::serial_fallback(), None))
+        }
+        // -j1, Make grants no tokens besides the implicit one
+        Ok(Some(client)) if client.is_serial() => {
+            eprintln!("warning: jobserver present, but parallelism is off (-j1), running serially");
+            Ok((client, None))
+        }
+        Ok(Some(client)) => {
+            let style = client.style().cloned();
+            Ok((client, style))
+        }
+        Err(err) => match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .and_then(JobServerError::from_io)
+        {
+            Some(JobServerError::OpenTimeout(_)) => {
+                eprintln!("warning: {err:#}, running serially");
+                Ok((Client::serial_fallback(), None))
+            }
+            Some(JobServerError::DescriptorNotInherited(fd)) if *fd < 0 => {
+                eprintln!(
+                    "warning: cannot use jobserver, because of negative pipe file descriptors"
+                );
+                Ok((Client::serial_fallback(), None))
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+// Acquires a token according to the policy, `None` if the jobserver
+// was closed meanwhile, the job then runs serially on its implicit
+// token.
+fn acquire(client: &Client) -> anyhow::Result<Option<Token>> {
+    match client.acquire_with_policy() {
+        Ok(Some(token)) => Ok(Some(token)),
+        Ok(None) => Err(anyhow::anyhow!("no token available").context(AcquireFailed)),
+        Err(err) if matches!(JobServerError::from_io(&err), Some(JobServerError::Closed)) => {
+            eprintln!("warning: {err}, running serially");
+            Ok(None)
+        }
+        Err(err) => Err(err).context(AcquireFailed),
+    }
+}
+
+// Acquires and releases a token `n` times in a row, the throughput
+// goes to stderr like the other diagnostics.
+fn bench(client: &Client, n: u32) -> anyhow::Result<()> {
+    let start = Instant::now();
+    for _ in 0..n {
+        let token = client.acquire().context(AcquireFailed)?;
+        client.release(token).context("releasing token")?;
+    }
+    let elapsed = start.elapsed();
+
+    eprintln!(
+        "{n} tokens in {:.3}s, {:.1} tokens/s",
+        elapsed.as_secs_f64(),
+        f64::from(n) / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+// Runs the command while holding a token.
+fn exec(client: &Client, command: &[String]) -> anyhow::Result<()> {
+    let token = acquire(client)?;
+    let status = Command::new(&command[0]).args(&command[1..]).status();
+    if let Some(token) = token {
+        client.release(token).context("releasing token")?;
+    }
+
+    let status = status.with_context(|| format!("running {}", command[0]))?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {status}", command[0]);
+    }
+
+    Ok(())
+}
+
+// Reads captured MAKEFLAGS, e.g. from `printenv MAKEFLAGS > file`, the
+// trailing newline isn't part of them.
+fn read_makeflags_file(path: &Path) -> anyhow::Result<OsString> {
+    let mut makeflags =
+        fs::read(path).with_context(|| format!("reading makeflags file {}", path.display()))?;
+    if makeflags.last() == Some(&b'\n') {
+        makeflags.pop();
+    }
+    Ok(OsString::from_vec(makeflags))
+}
+
+fn exit_code(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<ParseJobserverAuthError>().is_some() {
+        EXIT_MALFORMED_MAKEFLAGS
+    } else if err.downcast_ref::<AcquireFailed>().is_some() {
+        EXIT_ACQUIRE_FAILED
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+pub(crate) fn main() -> ExitCode {
+    // diagnostics of the library go to stderr, stdout is for the token
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // the same report as returning the error from main
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+// Runs the mode for the job server given by --fifo or --pipe, like
+// for one parsed from MAKEFLAGS.
+fn run_explicit(args: &Args, style: &JobServerStyleOwned) -> anyhow::Result<()> {
+    match args.mode {
+        Mode::Acquire | Mode::Bench(_) => {}
+        Mode::Protocol => {
+            println!("{}", style_name(style));
+            return Ok(());
+        }
+        Mode::Check => {
+            println_os(&check_style(style)?)?;
+            return Ok(());
+        }
+        Mode::Diagnose | Mode::DryRun => {
+            let mut report = vec![("style", style.to_auth())];
+            if args.mode == Mode::Diagnose {
+                probe(style, &mut report);
+            }
+            print_report(report)?;
+            return Ok(());
+        }
+    }
+
+    let client = builder(args)?
+        .build_owned(style)
+        .context("connecting to jobserver")?;
+    if let Mode::Bench(n) = args.mode {
+        return bench(&client, n);
+    }
+    hold_token(args, &client, style)
+}
+
+// The builder of the client to acquire with.
+fn builder(args: &Args) -> anyhow::Result<ClientBuilder> {
+    let mut builder = Client::builder().policy(args.policy);
+    if let Some(path) = &args.trace_file {
+        let audit = TraceAudit::create(path)
+            .with_context(|| format!("opening trace file {}", path.display()))?;
+        builder = builder.audit(audit);
+    }
+    Ok(builder)
+}
+
+// Runs the command of --exec while holding a token, or prints the
+// token.
+fn hold_token(args: &Args, client: &Client, style: &JobServerStyleOwned) -> anyhow::Result<()> {
+    if let Some(command) = &args.exec {
+        return exec(client, command);
+    }
+
+    // try to get the token
+    let Some(token) = acquire(client)? else {
+        return Ok(());
+    };
+    if !args.quiet {
+        println!("{}", format_token(&args.format, style, token.as_ref()));
+    }
+    client.release(token).context("releasing token")?;
+
+    Ok(())
+}
+
+fn run() -> anyhow::Result<()> {
+    let args = Args::parse(env::args().skip(1))?;
+    if let Some(style) = &args.style {
+        return run_explicit(&args, style);
+    }
+
+    let makeflags = match &args.makeflags_file {
+        Some(path) => read_makeflags_file(path)?,
+        None => {
+            env::var_os(args.makeflags_var.as_deref().unwrap_or("MAKEFLAGS")).unwrap_or_default()
+        }
+    };
+    match args.mode {
+        Mode::Acquire | Mode::Bench(_) => {}
+        Mode::Protocol => {
+            match classify_jobserver_protocol(&makeflags.to_string_lossy()) {
+                Some(protocol) => println!("{protocol}"),
+                None => println!("none"),
+            }
+            return Ok(());
+        }
+        Mode::Check => {
+            println_os(&check(&makeflags)?)?;
+            return Ok(());
+        }
+        Mode::Diagnose | Mode::DryRun => {
+            let dry_run = args.mode == Mode::DryRun || args.makeflags_file.is_some();
+            print_report(diagnose(&makeflags, dry_run))?;
+            return Ok(());
+        }
+    }
+
+    let var = args
+        .makeflags_file
+        .is_none()
+        .then(|| args.makeflags_var.as_deref().unwrap_or("MAKEFLAGS"));
+    let (client, job_server_style) = connect(&makeflags, var, builder(&args)?, args.quiet)?;
+    if let Mode::Bench(n) = args.mode {
+        return bench(&client, n);
+    }
+
+    match job_server_style {
+        Some(job_server_style) => hold_token(&args, &client, &job_server_style),
+        // the serial fallback, the command still runs
+        None => match &args.exec {
+            Some(command) => exec(&client, command),
+            None => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            super::Args::parse(std::iter::empty()).unwrap(),
+            super::Args::default()
+        );
+        assert_eq!(
+            super::Args::parse(["--format", "json"].into_iter().map(String::from))
+                .unwrap()
+                .format,
+            super::Format::Json
+        );
+        assert!(super::Args::parse(["--format", "xml"].into_iter().map(String::from)).is_err());
+        assert!(super::Args::parse(["--format"].into_iter().map(String::from)).is_err());
+        assert_eq!(
+            super::Args::parse(["--exec", "--", "true", "-x"].into_iter().map(String::from))
+                .unwrap()
+                .exec,
+            Some(vec![String::from("true"), String::from("-x")])
+        );
+        assert!(super::Args::parse(["--exec", "--"].into_iter().map(String::from)).is_err());
+        assert_eq!(
+            super::Args::parse(["--protocol"].into_iter().map(String::from))
+                .unwrap()
+                .mode,
+            super::Mode::Protocol
+        );
+        assert_eq!(
+            super::Args::parse(["--check", "--diagnose"].into_iter().map(String::from))
+                .unwrap()
+                .mode,
+            super::Mode::Diagnose
+        );
+        assert_eq!(
+            super::Args::parse(
+                ["--fail-fast", "--wait-forever"]
+                    .into_iter()
+                    .map(String::from)
+            )
+            .unwrap()
+            .policy,
+            super::AcquirePolicy::Block
+        );
+        assert_eq!(
+            super::Args::parse(["--fail-fast"].into_iter().map(String::from))
+                .unwrap()
+                .policy,
+            super::AcquirePolicy::FailFast
+        );
+        assert_eq!(
+            super::Args::parse(["--timeout", "250"].into_iter().map(String::from))
+                .unwrap()
+                .policy,
+            super::AcquirePolicy::Timeout(super::Duration::from_millis(250))
+        );
+        assert!(super::Args::parse(["--timeout", "soon"].into_iter().map(String::from)).is_err());
+        assert!(super::Args::parse(["--timeout"].into_iter().map(String::from)).is_err());
+        assert_eq!(
+            super::Args::parse(["--makeflags-var", "MFLAGS"].into_iter().map(String::from))
+                .unwrap()
+                .makeflags_var
+                .as_deref(),
+            Some("MFLAGS")
+        );
+        assert!(super::Args::parse(["--makeflags-var"].into_iter().map(String::from)).is_err());
+        assert_eq!(
+            super::Args::parse(["--trace-file", "trace.log"].into_iter().map(String::from))
+                .unwrap()
+                .trace_file,
+            Some(super::PathBuf::from("trace.log"))
+        );
+        assert!(super::Args::parse(["--trace-file"].into_iter().map(String::from)).is_err());
+        assert!(
+            super::Args::parse(["--quiet"].into_iter().map(String::from))
+                .unwrap()
+                .quiet
+        );
+        let args = super::Args::parse(["--makeflags-file", "flags"].into_iter().map(String::from))
+            .unwrap();
+        assert_eq!(args.makeflags_file, Some(super::PathBuf::from("flags")));
+        assert_eq!(args.mode, super::Mode::DryRun);
+        let args = super::Args::parse(
+            ["--diagnose", "--makeflags-file", "flags"]
+                .into_iter()
+                .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(args.mode, super::Mode::Diagnose);
+        for args in [
+            &["--makeflags-file", "flags", "--makeflags-var", "MFLAGS"][..],
+            &["--makeflags-file", "flags", "--check"],
+            &["--makeflags-file", "flags", "--bench", "10"],
+            &["--makeflags-file", "flags", "--exec", "true"],
+            &["--makeflags-file"],
+        ] {
+            assert!(super::Args::parse(args.iter().copied().map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_args_explicit_style() {
+        assert_eq!(
+            super::Args::parse(
+                ["--pipe", "3,4", "--fifo", "/tmp/GMfifo1"]
+                    .into_iter()
+                    .map(String::from)
+            )
+            .unwrap()
+            .style,
+            Some(super::JobServerStyleOwned::Fifo("/tmp/GMfifo1".into()))
+        );
+        assert_eq!(
+            super::Args::parse(["--pipe", "3,4"].into_iter().map(String::from))
+                .unwrap()
+                .style,
+            Some(super::JobServerStyleOwned::Pipe(3, 4))
+        );
+        for args in [
+            &["--fifo"][..],
+            &["--fifo", "relative/GMfifo1"],
+            &["--pipe", "3"],
+            &["--pipe", "3,x"],
+            &["--pipe", "3,4", "--makeflags-var", "MFLAGS"],
+            &["--fifo", "/tmp/GMfifo1", "--makeflags-file", "flags"],
+        ] {
+            assert!(super::Args::parse(args.iter().copied().map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_args_bench() {
+        assert_eq!(
+            super::Args::parse(["--bench", "10"].into_iter().map(String::from))
+                .unwrap()
+                .mode,
+            super::Mode::Bench(10)
+        );
+        for args in [
+            &["--bench"][..],
+            &["--bench", "0"],
+            &["--bench", "many"],
+            &["--bench", "10", "--exec", "true"],
+        ] {
+            assert!(super::Args::parse(args.iter().copied().map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let err = anyhow::Error::from(super::ParseJobserverAuthError::EmptyFifoPath)
+            .context("parsing jobserver auth");
+        assert_eq!(super::exit_code(&err), super::EXIT_MALFORMED_MAKEFLAGS);
+        let err = anyhow::anyhow!("no token available").context(super::AcquireFailed);
+        assert_eq!(super::exit_code(&err), super::EXIT_ACQUIRE_FAILED);
+        let err = anyhow::anyhow!("true failed with exit status: 1");
+        assert_eq!(super::exit_code(&err), super::EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_diagnose_fifo_pid() {
+        let report = super::diagnose(
+            super::OsStr::new(" -j2 --jobserver-auth=fifo:/tmp/GMfifo6851"),
+            true,
+        );
+        assert!(report.contains(&("fifo pid", "6851".into())));
+        let report = super::diagnose(
+            super::OsStr::new(" -j2 --jobserver-auth=fifo:/tmp/fifo"),
+            true,
+        );
+        assert!(report.contains(&("fifo pid", "unknown".into())));
+    }
+
+    #[test]
+    fn test_diagnose_style_auth() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // the style is the auth as in MAKEFLAGS, a space stays escaped
+        // and other bytes are kept
+        let report = super::diagnose(
+            super::OsStr::from_bytes(b" -j2 --jobserver-auth=fifo:/tmp/a\\ b\xff"),
+            true,
+        );
+        assert!(report.contains(&(
+            "style",
+            super::OsStr::from_bytes(b"fifo:/tmp/a\\ b\xff").into()
+        )));
+    }
+
+    #[test]
+    fn test_format_token_json() {
+        assert_eq!(
+            super::format_token(
+                &super::Format::Json,
+                &super::JobServerStyleOwned::Pipe(3, 4),
+                b"+"
+            ),
+            r#"{"style":"pipe","token":[43]}"#
+        );
+        assert_eq!(
+            super::format_token(
+                &super::Format::Json,
+                &super::JobServerStyleOwned::Fifo("/tmp/GMfifo6851".into()),
+                b"AB"
+            ),
+            r#"{"style":"fifo","token":[65,66]}"#
+        );
+        assert_eq!(
+            super::format_token(
+                &super::Format::Text,
+                &super::JobServerStyleOwned::Pipe(3, 4),
+                b"+"
+            ),
+            "+"
+        );
+    }
+
+    #[test]
+    fn test_probe_not_a_pipe() {
+        use std::os::{fd::AsRawFd, unix::net::UnixStream};
+
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let fd = socket.as_raw_fd();
+        let mut report = Vec::new();
+        super::probe(&super::JobServerStyleOwned::Pipe(fd, fd), &mut report);
+        assert_eq!(
+            report,
+            [
+                (
+                    "open",
+                    format!("failed (jobserver fd {fd} is a socket, not a pipe)").into()
+                ),
+                ("fd type", format!("socket (fd {fd})").into()),
+            ]
+        );
+    }
+}
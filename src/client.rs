@@ -0,0 +1,2271 @@
+//! A client of the job server, owning its handles to the job server.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+    task::{Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    acquire_token,
+    builder::ClientBuilder,
+    release_token,
+    transport::{read_rest, warn_zero_token, FileTransport},
+    AcquirePolicy, JobServerError, JobServerStyle, JobServerStyleOwned, Token, TokenAudit,
+    WaitStrategy,
+};
+
+// the upper bound of the sleeps between retries in acquire_backoff
+const MAX_BACKOFF: Duration = Duration::from_millis(64);
+// how long opening the fifo may take, e.g. on a stuck NFS mount
+const FIFO_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+// how long acquire_cancellable waits before checking the flag again
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A connection to the job server, tokens are acquired from it and
+/// released to it.
+#[derive(Debug)]
+pub struct Client {
+    connection: Arc<Connection>,
+    // the style connected to, None for the serial fallback
+    style: Option<JobServerStyleOwned>,
+    // the -jN of MAKEFLAGS, if the client was created from it
+    pub(crate) job_count: Option<usize>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) strategy: WaitStrategy,
+    pub(crate) policy: AcquirePolicy,
+    pub(crate) implicit_token: bool,
+    pub(crate) token_size: usize,
+    pub(crate) validate_release: bool,
+    // the options it was built with, a reconnect builds with them again
+    pub(crate) builder: ClientBuilder,
+}
+
+impl Client {
+    /// Connects to the job server described by `style` with the
+    /// default options, see [`ClientBuilder`] for the others.
+    ///
+    /// For the pipe style the inherited file descriptors are
+    /// duplicated, so dropping the client only closes our copies and
+    /// never the descriptors Make handed to us.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the FIFO cannot be opened or the pipe file descriptors
+    /// cannot be duplicated, e.g. because they were not inherited.
+    pub fn new(style: &JobServerStyle<'_>) -> io::Result<Self> {
+        ClientBuilder::new().build(style)
+    }
+
+    /// Connects to the job server advertised in the `MAKEFLAGS`
+    /// environment variable with the default options, returns `None`
+    /// if there's none.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_env`].
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        ClientBuilder::new().from_env()
+    }
+
+    /// Connects to the job server advertised in `makeflags` with the
+    /// default options, returns `None` if there's none. Unlike
+    /// [`Client::from_env`] the environment isn't read, e.g. for
+    /// embedders which have `MAKEFLAGS` from a config at hand.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_makeflags`].
+    pub fn from_makeflags(makeflags: &str) -> anyhow::Result<Option<Self>> {
+        ClientBuilder::new().from_makeflags(makeflags)
+    }
+
+    /// Connects to the job server advertised in the environment
+    /// variable `name` instead of `MAKEFLAGS`, e.g. `MFLAGS` or the
+    /// variable of a wrapper tool, with the default options.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ClientBuilder::from_env`].
+    pub fn from_env_var(name: &str) -> anyhow::Result<Option<Self>> {
+        ClientBuilder::new().from_env_var(name)
+    }
+
+    /// A client without a job server which grants exactly one token,
+    /// the implicit one every job has. It lets tools work standalone
+    /// outside of Make by running serially. The token is empty, while
+    /// it's acquired further acquisitions wait for its release.
+    #[must_use]
+    pub fn serial_fallback() -> Self {
+        Self {
+            connection: Arc::new(Connection {
+                transport: None,
+                implicit_available: AtomicBool::new(true),
+                implicit_waiters: Mutex::default(),
+                fifo_path: None,
+                audit: None,
+                pending: Mutex::new(Vec::new()),
+                outstanding: Mutex::default(),
+                next_token_id: AtomicU64::new(0),
+            }),
+            style: None,
+            job_count: None,
+            timeout: None,
+            strategy: WaitStrategy::default(),
+            policy: AcquirePolicy::default(),
+            implicit_token: false,
+            token_size: 1,
+            validate_release: false,
+            builder: ClientBuilder::default(),
+        }
+    }
+
+    /// Reconnects to the job server advertised in `MAKEFLAGS`, see
+    /// [`Client::reconnect_to`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::reconnect_to`].
+    pub fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.reconnect_to_os(&std::env::var_os("MAKEFLAGS").unwrap_or_default())
+    }
+
+    /// Reopens the connection to the job server advertised in
+    /// `makeflags`, e.g. when acquiring fails with
+    /// [`JobServerError::Closed`] because Make recreated the FIFO
+    /// between builds in watch mode. The client is built again with
+    /// the options of its [`ClientBuilder`], like
+    /// [`ClientBuilder::from_makeflags`], so with `-j1` it becomes the
+    /// [`Client::serial_fallback`].
+    ///
+    /// Tokens still held from the old connection are released to the
+    /// old job server, which may be gone already.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `makeflags` advertises no job server or connecting
+    /// fails, the client keeps its old connection then.
+    pub fn reconnect_to(&mut self, makeflags: &str) -> anyhow::Result<()> {
+        self.reconnect_to_os(OsStr::new(makeflags))
+    }
+
+    // reconnect_to with the raw bytes of the environment, so a FIFO
+    // path that isn't UTF-8 is kept verbatim
+    fn reconnect_to_os(&mut self, makeflags: &OsStr) -> anyhow::Result<()> {
+        let fresh = self
+            .builder
+            .clone()
+            .from_makeflags_os(makeflags)
+            .context("reconnecting to jobserver")?
+            .context("no jobserver to reconnect to")?;
+
+        *self = fresh;
+        Ok(())
+    }
+
+    /// Whether this is the [`Client::serial_fallback`] without a job
+    /// server.
+    #[must_use]
+    pub fn is_serial(&self) -> bool {
+        self.connection.transport.is_none()
+    }
+
+    /// The style of the job server the client is connected to, `None`
+    /// for the serial fallback. It's the style as parsed, for the pipe
+    /// style these are the inherited descriptors, not our duplicates,
+    /// and a FIFO path is canonicalized if the builder was told so.
+    #[must_use]
+    pub fn style(&self) -> Option<&JobServerStyleOwned> {
+        self.style.as_ref()
+    }
+
+    /// The `-jN` of `MAKEFLAGS`, if the client was created from it and
+    /// it was given.
+    #[must_use]
+    pub fn parallelism(&self) -> Option<usize> {
+        self.job_count
+    }
+
+    /// The number of tokens Make put into the job server, `N - 1` for
+    /// `-jN`, as the top-level Make holds the implicit token. It's only
+    /// an estimate derived from [`Client::parallelism`] to size data
+    /// structures, not a count of the tokens available now, see
+    /// [`available_token_estimate`](crate::available_token_estimate).
+    #[must_use]
+    pub fn initial_token_estimate(&self) -> Option<usize> {
+        self.job_count.map(|job_count| job_count.saturating_sub(1))
+    }
+
+    /// Creates a builder to configure the client.
+    #[must_use]
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// Shares the job server with a child, e.g. a sub-make, by setting
+    /// its `MAKEFLAGS`. For the pipe style our descriptors are
+    /// inherited by the child, they stay close-on-exec for every other
+    /// command. The serial fallback leaves the command untouched.
+    pub fn configure_command(&self, cmd: &mut Command) {
+        let Some(makeflags) = self.build_child_makeflags() else {
+            return;
+        };
+        cmd.env("MAKEFLAGS", makeflags);
+
+        if self.connection.fifo_path.is_none() {
+            if let Some(transport) = &self.connection.transport {
+                let fds = [transport.read.as_raw_fd(), transport.write_fd()];
+                // SAFETY: fcntl is async-signal-safe and doesn't
+                // allocate, the fds are kept open by self until spawn
+                unsafe {
+                    cmd.pre_exec(move || {
+                        for fd in fds {
+                            clear_cloexec(fd)?;
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+    }
+
+    /// The `MAKEFLAGS` advertising our job server, for setting up a
+    /// child's environment manually, [`Client::configure_command`]
+    /// does it for a [`Command`]. For the pipe style these are the
+    /// numbers of our duplicated descriptors, which the child must
+    /// inherit. A FIFO path is escaped like Make does and may be any
+    /// bytes. It's empty for the serial fallback.
+    #[must_use]
+    pub fn makeflags(&self) -> OsString {
+        self.build_child_makeflags().unwrap_or_default()
+    }
+
+    // The MAKEFLAGS advertising our job server to a child.
+    fn build_child_makeflags(&self) -> Option<OsString> {
+        let transport = self.connection.transport.as_ref()?;
+        let style = match &self.connection.fifo_path {
+            Some(fifo_path) => JobServerStyleOwned::Fifo(fifo_path.clone()),
+            None => JobServerStyleOwned::Pipe(transport.read.as_raw_fd(), transport.write_fd()),
+        };
+        let jobs = self
+            .job_count
+            .map(|job_count| format!(" -j{job_count}"))
+            .unwrap_or_default();
+
+        let mut makeflags = OsString::from(format!("{jobs} --jobserver-auth="));
+        makeflags.push(style.to_auth());
+        Some(makeflags)
+    }
+
+    // The inherited pipe fds are duplicated right here and from then on
+    // only our owned copies are used, never the numbers parsed from
+    // MAKEFLAGS. If a buggy wrapper closes and reuses those numbers
+    // later, we still talk to the job server we connected to.
+    pub(crate) fn connect(style: &JobServerStyleOwned) -> io::Result<Self> {
+        let mut fifo_path = None;
+        let (read, write) = match style {
+            JobServerStyleOwned::Fifo(fifo_file) => {
+                fifo_path = Some(fifo_file.clone());
+                open_fifo(fifo_file, FIFO_OPEN_TIMEOUT)?
+            }
+            JobServerStyleOwned::Pipe(read_fd, write_fd) if read_fd == write_fd => {
+                // Make always passes the two distinct ends of a pipe,
+                // with a single fd we may read back the token we just
+                // released, so this is most likely a broken wrapper
+                tracing::warn!(
+                    fd = read_fd,
+                    "jobserver uses the same fd for reading and writing"
+                );
+                // a single bidirectional fd, e.g. a FIFO passed as fd,
+                // is duplicated only once and shared by both sides
+                let read: File = dup_inherited(*read_fd)?.into();
+                check_pipe(&read, *read_fd)?;
+                let write = read.try_clone()?;
+                (read, write)
+            }
+            JobServerStyleOwned::Pipe(read_fd, write_fd) => {
+                let read: File = dup_inherited(*read_fd)?.into();
+                check_pipe(&read, *read_fd)?;
+                let write: File = dup_inherited(*write_fd)?.into();
+                check_pipe(&write, *write_fd)?;
+                (read, write)
+            }
+        };
+
+        Ok(Self {
+            connection: Arc::new(Connection {
+                transport: Some(FileTransport::new(read, write)),
+                implicit_available: AtomicBool::new(false),
+                implicit_waiters: Mutex::default(),
+                fifo_path,
+                audit: None,
+                pending: Mutex::new(Vec::new()),
+                outstanding: Mutex::default(),
+                next_token_id: AtomicU64::new(0),
+            }),
+            style: Some(style.clone()),
+            job_count: None,
+            timeout: None,
+            strategy: WaitStrategy::default(),
+            policy: AcquirePolicy::default(),
+            implicit_token: false,
+            token_size: 1,
+            validate_release: false,
+            builder: ClientBuilder::default(),
+        })
+    }
+
+    /// Acquires a token, blocks until one is available. A token has
+    /// the size configured with [`ClientBuilder::token_size`]. The
+    /// serial fallback waits until its implicit token is dropped, it
+    /// must be held by another thread then.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails, with
+    /// [`JobServerError::Closed`] if the job server was closed.
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn acquire(&self) -> io::Result<Token> {
+        let Some(mut transport) = self.connection.transport.as_ref() else {
+            let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+            loop {
+                if let Some(token) = self.poll_implicit(&waker) {
+                    return Ok(token);
+                }
+                // woken by the release, or spuriously, then it's retried
+                thread::park();
+            }
+        };
+
+        let bytes = self
+            .connection
+            .timed("jobserver_acquire_wait_seconds", || {
+                acquire_token(&mut transport, self.token_size)
+            })?;
+        Ok(Token::new(bytes, Arc::clone(&self.connection)))
+    }
+
+    /// Acquires `n` tokens, blocks until all are available.
+    ///
+    /// With [`ClientBuilder::implicit_token`] the first token is the
+    /// implicit one Make grants every job, it isn't read from the job
+    /// server. The returned tokens are one short then, requesting 3
+    /// tokens reads only 2. An interrupted read is retried. If acquiring
+    /// fails midway, the tokens read so far are returned, fewer than
+    /// requested, so the caller can retry for the rest or release them.
+    ///
+    /// The serial fallback returns at most its implicit token, there's
+    /// no other one to wait for.
+    ///
+    /// The tokens are returned last acquired first. A `Vec` drops its
+    /// elements front to back, so dropping it releases them in LIFO
+    /// order, the job server gets the token it handed out last back
+    /// first.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading the first token from the job server fails.
+    #[must_use = "the tokens are released right away if they're not held"]
+    pub fn acquire_tokens(&self, n: usize) -> io::Result<Vec<Token>> {
+        let n_read = if self.implicit_token {
+            n.saturating_sub(1)
+        } else {
+            n
+        };
+        let n_read = if self.is_serial() {
+            n_read.min(1)
+        } else {
+            n_read
+        };
+
+        let mut tokens = Vec::with_capacity(n_read);
+        while tokens.len() < n_read {
+            match self.acquire() {
+                Ok(token) => tokens.push(token),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if tokens.is_empty() => return Err(err),
+                Err(err) => {
+                    tracing::warn!(
+                        %err,
+                        acquired = tokens.len(),
+                        requested = n_read,
+                        "acquiring tokens failed midway"
+                    );
+                    break;
+                }
+            }
+        }
+        tokens.reverse();
+        Ok(tokens)
+    }
+
+    /// Runs the tasks in parallel, each on its own thread while holding
+    /// a token, which is dropped once the task finished, even if it
+    /// panics. The concurrency is bounded by the tokens obtainable,
+    /// the implicit token of the calling job runs one task, every
+    /// other needs a token from the job server.
+    ///
+    /// # Errors
+    ///
+    /// Fails if acquiring a token fails, the tasks started so far run
+    /// to completion nonetheless.
+    pub fn run_parallel<F>(&self, tasks: Vec<F>) -> io::Result<()>
+    where
+        F: FnOnce() + Send,
+    {
+        // the serial fallback hands out the implicit token itself
+        let implicit_available = AtomicBool::new(!self.is_serial());
+        thread::scope(|scope| {
+            for task in tasks {
+                let token = loop {
+                    if implicit_available
+                        .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        break None;
+                    }
+                    if let Some(token) = self.acquire_backoff(MAX_BACKOFF)? {
+                        break Some(token);
+                    }
+                };
+
+                let slot = Slot {
+                    token,
+                    implicit_available: &implicit_available,
+                };
+                scope.spawn(move || {
+                    let _slot = slot;
+                    task();
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Acquires a token if one is available right away, never blocks.
+    ///
+    /// The job server is read through a non-blocking file description
+    /// of our own, the one shared with Make stays blocking. Only where
+    /// it cannot be reopened, without `/proc`, the non-blocking mode
+    /// is toggled on the shared one. Only the first byte of a token is
+    /// read non-blocking, the writer writes a token at once, so the
+    /// rest is already there. If reading the rest fails, the first byte
+    /// is written back.
+    ///
+    /// Releasing the token doesn't block either. If the pipe is full,
+    /// the release is queued and retried with the next one, the queue
+    /// is flushed when the last token and the client are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails or the job server
+    /// was closed.
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn try_acquire(&self) -> io::Result<Option<Token>> {
+        let Some(mut transport) = self.connection.transport.as_ref() else {
+            return Ok(self.try_acquire_implicit());
+        };
+
+        let mut token = vec![0; self.token_size.max(1)];
+        match transport.read_nonblocking(&mut token[..1]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if let Err(err) = read_rest(&transport.read, &mut token, 1) {
+            // the token is lost otherwise
+            if let Err(release_err) = release_token(&mut transport, &token[..1]) {
+                tracing::warn!(%release_err, "writing back the partial token failed");
+            }
+            return Err(err);
+        }
+        warn_zero_token(&token);
+
+        Ok(Some(
+            Token::new(token, Arc::clone(&self.connection)).with_nonblocking_release(),
+        ))
+    }
+
+    /// Acquires a token if one is free, otherwise the caller runs the
+    /// job serially on its implicit token, which is `None`. It never
+    /// blocks, so the build always makes progress, also if the job
+    /// server was closed meanwhile, which is logged and gives `None`
+    /// too.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails otherwise.
+    #[must_use = "without a token the job runs serially, it's released right away if it's not held"]
+    pub fn acquire_or_serial(&self) -> io::Result<Option<Token>> {
+        match self.try_acquire() {
+            Err(err) if matches!(JobServerError::from_io(&err), Some(JobServerError::Closed)) => {
+                tracing::warn!(%err, "running serially");
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Acquires every token available right away with
+    /// [`Client::try_acquire`], e.g. for a supervisor which shuts down
+    /// and doesn't want new jobs to start. The tokens are released when
+    /// they're dropped.
+    ///
+    /// Holding all tokens starves the whole build, every other job
+    /// waits for them, so only drain in controlled scenarios and
+    /// release the tokens as soon as possible.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::try_acquire`], the tokens acquired so far
+    /// are released then.
+    #[must_use = "the tokens are released right away if they're not held"]
+    pub fn drain(&self) -> io::Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+        while let Some(token) = self.try_acquire()? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    /// Counts the tokens available right away by acquiring them all
+    /// with [`Client::drain`] and releasing them again, e.g. for
+    /// capacity planning. The serial fallback counts its implicit token.
+    ///
+    /// This is intrusive, while the tokens are held no other job can
+    /// start, and racy, other clients acquire and release meanwhile. It's
+    /// for diagnostics only, [`available_token_estimate`] counts without
+    /// acquiring anything.
+    ///
+    /// [`available_token_estimate`]: crate::available_token_estimate
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::try_acquire`] and [`Client::release_all`].
+    /// The tokens acquired so far are released on failure, so the
+    /// build keeps its parallelism, unless the release itself fails.
+    pub fn drain_and_count(&self) -> io::Result<usize> {
+        let tokens = self.drain()?;
+        let n_tokens = tokens.len();
+        self.release_all(tokens)?;
+        Ok(n_tokens)
+    }
+
+    /// Acquires a token, retrying [`Client::try_acquire`] with
+    /// exponentially increasing sleeps in between (1ms, 2ms, 4ms, ...
+    /// capped at 64ms). It's a middle ground between blocking and
+    /// spinning on a saturated job server. Returns `None` if no token
+    /// became available within `max_wait`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::try_acquire`].
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn acquire_backoff(&self, max_wait: Duration) -> io::Result<Option<Token>> {
+        let deadline = Instant::now() + max_wait;
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            if let Some(token) = self.try_acquire()? {
+                return Ok(Some(token));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Acquires a token, waits at most for the timeout configured with
+    /// [`ClientBuilder::timeout`] using the configured
+    /// [`WaitStrategy`]. Returns `None` on timeout, without a timeout
+    /// it blocks like [`Client::acquire`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails.
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn acquire_timeout(&self) -> io::Result<Option<Token>> {
+        match self.timeout {
+            Some(timeout) => self.acquire_within(timeout),
+            None => self.acquire().map(Some),
+        }
+    }
+
+    /// Acquires a token according to the [`AcquirePolicy`] configured
+    /// with [`ClientBuilder::policy`]. Returns `None` if no token is
+    /// available right away with [`AcquirePolicy::FailFast`] or within
+    /// the duration of [`AcquirePolicy::Timeout`], never with
+    /// [`AcquirePolicy::Block`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails.
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn acquire_with_policy(&self) -> io::Result<Option<Token>> {
+        match self.policy {
+            AcquirePolicy::Block => self.acquire().map(Some),
+            AcquirePolicy::FailFast => self.try_acquire(),
+            AcquirePolicy::Timeout(timeout) => self.acquire_within(timeout),
+        }
+    }
+
+    fn acquire_within(&self, timeout: Duration) -> io::Result<Option<Token>> {
+        match (self.strategy, &self.connection.transport) {
+            (WaitStrategy::Poll, Some(transport)) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(token) = self.try_acquire()? {
+                        return Ok(Some(token));
+                    }
+
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    poll_readable(transport.read.as_raw_fd(), deadline - now)?;
+                }
+            }
+            // there's nothing to poll without a job server
+            (WaitStrategy::Backoff, _) | (_, None) => self.acquire_backoff(timeout),
+        }
+    }
+
+    /// Acquires a token like [`Client::acquire`], but gives up once
+    /// `cancel` is set, e.g. by a scheduler which shuts down, and
+    /// returns `None` then. The job server is polled in short
+    /// intervals, so setting the flag is noticed within about 50 ms.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails, with
+    /// [`JobServerError::Closed`] if the job server was closed.
+    #[must_use = "the token is released right away if it's not held"]
+    pub fn acquire_cancellable(&self, cancel: &AtomicBool) -> io::Result<Option<Token>> {
+        loop {
+            if cancel.load(Ordering::Acquire) {
+                return Ok(None);
+            }
+            if let Some(token) = self.try_acquire()? {
+                return Ok(Some(token));
+            }
+
+            match &self.connection.transport {
+                Some(transport) => {
+                    poll_readable(transport.read.as_raw_fd(), CANCEL_POLL_INTERVAL)?;
+                }
+                None => thread::sleep(CANCEL_POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Acquires a token and releases it right away, returns the time
+    /// the round trip took, e.g. to measure the latency of the job
+    /// server. It blocks like [`Client::acquire`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from or writing to the job server fails.
+    pub fn ping(&self) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.acquire()?.release()?;
+        Ok(start.elapsed())
+    }
+
+    /// Releases a token previously acquired from this job server, like
+    /// [`Token::release`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to the job server fails. With
+    /// [`ClientBuilder::validate_release`] it also fails if the token
+    /// was acquired from another client, it's released to its own job
+    /// server nonetheless.
+    pub fn release(&self, token: Token) -> io::Result<()> {
+        if self.validate_release && !token.is_from(&self.connection) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the token was acquired from another client",
+            ));
+        }
+        token.release()
+    }
+
+    /// Releases all tokens, e.g. those of [`Client::acquire_tokens`].
+    /// A failing release doesn't stop the others, so one bad write
+    /// doesn't leak the remaining tokens.
+    ///
+    /// # Errors
+    ///
+    /// Fails if releasing any token fails, the error names all
+    /// failures and has the kind of the first one.
+    pub fn release_all(&self, tokens: Vec<Token>) -> io::Result<()> {
+        let n_tokens = tokens.len();
+        let errors: Vec<_> = tokens
+            .into_iter()
+            .filter_map(|token| self.release(token).err())
+            .collect();
+
+        release_errors(&errors, n_tokens)
+    }
+
+    /// Releases every token acquired from this job server which wasn't
+    /// released yet, wherever it's held, and returns their number. A
+    /// token released this way isn't released again when it's dropped.
+    ///
+    /// It's a safety net for a panic hook, tokens are released on drop
+    /// while unwinding, but not with `panic = "abort"` or if they were
+    /// leaked. The hook has to share the client:
+    ///
+    /// ```no_run
+    /// # fn run() -> anyhow::Result<()> {
+    /// use std::{panic, sync::Arc};
+    ///
+    /// let Some(client) = jobserver_demo_client_rs::Client::from_env()? else {
+    ///     return Ok(());
+    /// };
+    /// let client = Arc::new(client);
+    /// let hook_client = Arc::clone(&client);
+    /// let previous_hook = panic::take_hook();
+    /// panic::set_hook(Box::new(move |info| {
+    ///     let _ = hook_client.release_outstanding();
+    ///     previous_hook(info);
+    /// }));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::release_all`].
+    pub fn release_outstanding(&self) -> io::Result<usize> {
+        let outstanding = mem::take(
+            &mut *self
+                .connection
+                .outstanding
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        let n_tokens = outstanding.len();
+        let errors: Vec<_> = outstanding
+            .values()
+            .filter_map(|token| self.connection.release(token).err())
+            .collect();
+
+        release_errors(&errors, n_tokens).map(|()| n_tokens)
+    }
+}
+
+// The error of releasing n_tokens at once, naming every failure.
+fn release_errors(errors: &[io::Error], n_tokens: usize) -> io::Result<()> {
+    let Some(first) = errors.first() else {
+        return Ok(());
+    };
+    let messages: Vec<_> = errors.iter().map(io::Error::to_string).collect();
+    Err(io::Error::new(
+        first.kind(),
+        format!(
+            "releasing {} of {n_tokens} tokens failed: {}",
+            errors.len(),
+            messages.join("; ")
+        ),
+    ))
+}
+
+/// The blocking interface of a job server client, for code which
+/// shouldn't depend on the client type of the platform, e.g. via a
+/// `Box<dyn JobServerClient>`. [`Client`] implements it with its
+/// inherent methods of the same names.
+pub trait JobServerClient {
+    /// Acquires a token, blocks until one is available.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails.
+    #[must_use = "the token is released right away if it's not held"]
+    fn acquire(&self) -> io::Result<Token>;
+
+    /// Acquires a token if one is available right away, never blocks.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails.
+    #[must_use = "the token is released right away if it's not held"]
+    fn try_acquire(&self) -> io::Result<Option<Token>>;
+
+    /// Releases a token previously acquired from this job server.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to the job server fails.
+    fn release(&self, token: Token) -> io::Result<()>;
+
+    /// Releases every token acquired and not yet released, e.g. from a
+    /// panic hook, see [`Client::release_outstanding`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to the job server fails.
+    fn release_outstanding(&self) -> io::Result<usize>;
+}
+
+impl JobServerClient for Client {
+    fn acquire(&self) -> io::Result<Token> {
+        Client::acquire(self)
+    }
+
+    fn try_acquire(&self) -> io::Result<Option<Token>> {
+        Client::try_acquire(self)
+    }
+
+    fn release(&self, token: Token) -> io::Result<()> {
+        Client::release(self, token)
+    }
+
+    fn release_outstanding(&self) -> io::Result<usize> {
+        Client::release_outstanding(self)
+    }
+}
+
+// The connection to the job server, shared by the client and the
+// tokens acquired from it, so a token can be released from any thread.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    // None for the serial fallback without a job server
+    transport: Option<FileTransport>,
+    // the implicit token of the serial fallback
+    implicit_available: AtomicBool,
+    // the clients awaiting the implicit token
+    implicit_waiters: Mutex<Vec<Waker>>,
+    // the path of the fifo style, advertised to children
+    fifo_path: Option<PathBuf>,
+    audit: Option<Arc<dyn TokenAudit>>,
+    // bytes of non-blocking releases the full pipe didn't take yet
+    pending: Mutex<Vec<u8>>,
+    // the tokens acquired and not yet released, by id
+    outstanding: Mutex<HashMap<u64, Vec<u8>>>,
+    next_token_id: AtomicU64,
+}
+
+impl Connection {
+    // Tracks the acquired token until it's released, returns its id.
+    pub(crate) fn on_acquire(&self, token: &[u8]) -> u64 {
+        if let Some(audit) = &self.audit {
+            audit.on_acquire(token);
+        }
+        let id = self.next_token_id.fetch_add(1, Ordering::Relaxed);
+        self.outstanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, token.to_vec());
+        id
+    }
+
+    // Whether the token was still outstanding, it isn't anymore.
+    pub(crate) fn untrack(&self, id: u64) -> bool {
+        self.outstanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id)
+            .is_some()
+    }
+
+    pub(crate) fn release(&self, token: &[u8]) -> io::Result<()> {
+        self.release_unaudited(token)?;
+        if let Some(audit) = &self.audit {
+            audit.on_release(token);
+        }
+        Ok(())
+    }
+
+    // Releases a token without blocking, if the pipe is full the bytes
+    // are queued and written with the next non-blocking release or
+    // when the connection is dropped.
+    pub(crate) fn release_nonblocking(&self, token: &[u8]) -> io::Result<()> {
+        let Some(transport) = self.transport.as_ref() else {
+            return self.release(token);
+        };
+
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.extend_from_slice(token);
+        let written = self.timed("jobserver_release_seconds", || {
+            transport.write_nonblocking(&pending)
+        });
+        let written = match written {
+            Ok(written) => written,
+            Err(err) => {
+                // the token is lost like with a failing blocking release
+                let len = pending.len();
+                pending.truncate(len - token.len());
+                return Err(err);
+            }
+        };
+        pending.drain(..written);
+        if !pending.is_empty() {
+            tracing::debug!(
+                queued = pending.len(),
+                "jobserver pipe is full, queued the token release"
+            );
+        }
+        drop(pending);
+
+        if let Some(audit) = &self.audit {
+            audit.on_release(token);
+        }
+        Ok(())
+    }
+
+    fn release_unaudited(&self, token: &[u8]) -> io::Result<()> {
+        let Some(mut transport) = self.transport.as_ref() else {
+            if !token.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the serial fallback only grants the empty implicit token",
+                ));
+            }
+            self.implicit_available.store(true, Ordering::Release);
+            let waiters = mem::take(
+                &mut *self
+                    .implicit_waiters
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            waiters.into_iter().for_each(Waker::wake);
+            return Ok(());
+        };
+
+        self.timed("jobserver_release_seconds", || {
+            release_token(&mut transport, token)
+        })
+    }
+
+    // Runs a job server operation, with the `metrics` feature its
+    // duration is recorded in the histogram `name`, keyed by style.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables, clippy::unused_self))]
+    fn timed<T>(&self, name: &'static str, operation: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let result = operation();
+        #[cfg(feature = "metrics")]
+        {
+            let style = if self.fifo_path.is_some() {
+                "fifo"
+            } else {
+                "pipe"
+            };
+            metrics::histogram!(name, "style" => style).record(start.elapsed().as_secs_f64());
+        }
+        result
+    }
+}
+
+impl Drop for Connection {
+    // The queued releases are written blocking, Make waits for them.
+    fn drop(&mut self) {
+        let pending = self
+            .pending
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if pending.is_empty() {
+            return;
+        }
+        if let Some(mut transport) = self.transport.as_ref() {
+            if let Err(err) = release_token(&mut transport, pending) {
+                tracing::warn!(%err, queued = pending.len(), "flushing queued token releases failed");
+            }
+        }
+    }
+}
+
+// A token held by a task of run_parallel, None is the implicit token,
+// which is given back on drop.
+struct Slot<'a> {
+    token: Option<Token>,
+    implicit_available: &'a AtomicBool,
+}
+
+// Wakes a thread parked in acquire on the serial fallback.
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if self.token.is_none() {
+            self.implicit_available.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Client {
+    // Only while the client is configured, before any token shares the
+    // connection.
+    pub(crate) fn set_audit(&mut self, audit: Option<Arc<dyn TokenAudit>>) {
+        Arc::get_mut(&mut self.connection)
+            .expect("no token acquired yet")
+            .audit = audit;
+    }
+
+    // Awaits the implicit token of the serial fallback for the async
+    // clients, which cannot park the thread like acquire does.
+    #[cfg(any(feature = "async-io", feature = "tokio"))]
+    pub(crate) async fn acquire_implicit(&self) -> Token {
+        use std::task::Poll;
+
+        std::future::poll_fn(|cx| {
+            self.poll_implicit(cx.waker())
+                .map_or(Poll::Pending, Poll::Ready)
+        })
+        .await
+    }
+
+    // Takes the implicit token of the serial fallback, or registers
+    // `waker` to be woken once it's released.
+    fn poll_implicit(&self, waker: &Waker) -> Option<Token> {
+        if let Some(token) = self.try_acquire_implicit() {
+            return Some(token);
+        }
+        let mut waiters = self
+            .connection
+            .implicit_waiters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !waiters.iter().any(|registered| registered.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+        drop(waiters);
+        // it may have been released before the waker was registered
+        self.try_acquire_implicit()
+    }
+
+    // The read end of the job server, whose readiness the async
+    // clients await, None for the serial fallback.
+    #[cfg(any(feature = "async-io", feature = "tokio"))]
+    pub(crate) fn read_handle(&self) -> Option<&File> {
+        self.connection
+            .transport
+            .as_ref()
+            .map(|transport| &transport.read)
+    }
+
+    fn try_acquire_implicit(&self) -> Option<Token> {
+        self.connection
+            .implicit_available
+            .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| Token::new(Vec::new(), Arc::clone(&self.connection)))
+    }
+}
+
+// Reopens the fd via /proc as a non-blocking open file description of
+// our own, the one shared with Make stays blocking.
+pub(crate) fn reopen_nonblocking(fd: RawFd, write: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    File::options()
+        .read(!write)
+        .write(write)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(format!("/proc/self/fd/{fd}"))
+}
+
+// Opens the read and the write handle of the fifo. The open runs in a
+// thread, so a hanging filesystem can't stall the build forever. On
+// timeout the thread is left behind.
+fn open_fifo(fifo_file: &Path, timeout: Duration) -> io::Result<(File, File)> {
+    let (sender, receiver) = mpsc::channel();
+    let path = fifo_file.to_owned();
+    thread::spawn(move || {
+        let fifo = open_fifo_split(&path).or_else(|err| {
+            // opening a fifo read-write is undefined by POSIX, but
+            // works on Linux
+            if cfg!(any(target_os = "linux", target_os = "android"))
+                && JobServerError::from_io(&err).is_none()
+            {
+                open_fifo_read_write(&path)
+            } else {
+                Err(err)
+            }
+        });
+        let _ = sender.send(fifo);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(fifo) => fifo,
+        Err(_) => Err(io::Error::other(JobServerError::OpenTimeout(
+            fifo_file.display().to_string(),
+        ))),
+    }
+}
+
+// Opens the fifo like Make does, read-only and write-only. Both opens
+// are non-blocking, so they don't wait for a peer, even if Make is
+// gone already. A blocking read-only open would wait for a writer, a
+// non-blocking write-only open fails with ENXIO without a reader, so
+// the reader is opened first. Tokens are acquired blocking by default.
+fn open_fifo_split(path: &Path) -> io::Result<(File, File)> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let read = File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    check_fifo(&read, path)?;
+    let write = File::options()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    set_nonblocking(read.as_raw_fd(), false)?;
+    set_nonblocking(write.as_raw_fd(), false)?;
+    Ok((read, write))
+}
+
+// Opens a single read-write handle of the fifo, shared by both sides.
+fn open_fifo_read_write(path: &Path) -> io::Result<(File, File)> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let fifo = File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    check_fifo(&fifo, path)?;
+    set_nonblocking(fifo.as_raw_fd(), false)?;
+    let write = fifo.try_clone()?;
+    Ok((fifo, write))
+}
+
+// A stale regular file opens fine, but reading a token from it never
+// works.
+fn check_fifo(file: &File, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file.metadata()?.file_type().is_fifo() {
+        Ok(())
+    } else {
+        Err(io::Error::other(JobServerError::NotAFifo(
+            path.display().to_string(),
+        )))
+    }
+}
+
+// Fails unless the inherited `fd` is a pipe, a FIFO passed as fd is one
+// too. The error names what it is instead.
+fn check_pipe(file: &File, fd: RawFd) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = file.metadata()?.file_type();
+    let kind = if file_type.is_fifo() {
+        return Ok(());
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_file() {
+        "regular file"
+    } else if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_char_device() {
+        // SAFETY: isatty only inspects the open fd
+        if unsafe { libc::isatty(file.as_raw_fd()) } == 1 {
+            "terminal"
+        } else {
+            "character device"
+        }
+    } else if file_type.is_block_device() {
+        "block device"
+    } else {
+        "file of unknown type"
+    };
+    Err(io::Error::other(JobServerError::NotAPipe(fd, kind)))
+}
+
+// Duplicates the inherited fd into a fresh descriptor owned by us.
+// The copy is close-on-exec, so it doesn't leak into grandchildren
+// unless it's explicitly shared.
+fn dup_inherited(fd: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: F_DUPFD_CLOEXEC doesn't touch any memory, an invalid fd
+    // is reported as EBADF
+    let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup_fd == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EBADF) {
+            return Err(io::Error::other(JobServerError::DescriptorNotInherited(fd)));
+        }
+        return Err(err);
+    }
+
+    // SAFETY: dup_fd is a fresh descriptor nobody else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(dup_fd) })
+}
+
+// Clears FD_CLOEXEC, so the fd is inherited by an exec'd program.
+fn clear_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: F_GETFD/F_SETFD only touch the fd flags
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Waits at most `timeout` for fd to become readable, a readable fd
+// doesn't guarantee a token, another client may be faster.
+fn poll_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms =
+        libc::c_int::try_from(timeout.as_micros().div_ceil(1000)).unwrap_or(libc::c_int::MAX);
+    // SAFETY: pollfd is a single valid pollfd
+    match unsafe { libc::poll(std::ptr::addr_of_mut!(pollfd), 1, timeout_ms) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+        ready => Ok(ready > 0),
+    }
+}
+
+// Sets or clears O_NONBLOCK, returns whether it was set before.
+pub(crate) fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<bool> {
+    // SAFETY: F_GETFL/F_SETFL don't touch any memory
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let new_flags = if nonblocking {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+    if new_flags != flags && unsafe { libc::fcntl(fd, libc::F_SETFL, new_flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(flags & libc::O_NONBLOCK != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{self, Read, Write},
+        os::{
+            fd::AsRawFd,
+            unix::{ffi::OsStrExt, net::UnixStream},
+        },
+        path::Path,
+        process::{self, Command},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Client, JobServerClient};
+    use crate::{
+        available_token_estimate, parse_jobserver_auth, parse_jobserver_auth_bytes,
+        test_util::{bidirectional_fifo, capture_logs, mkfifo, pipe, small_pipe, TestFifo},
+        AcquirePolicy, JobServerError, JobServerStyle, JobServerStyleOwned,
+    };
+
+    #[test]
+    fn test_pipe_fds_stay_open_after_drop() {
+        let (mut file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+
+        let client = Client::new(&style).unwrap();
+        file_write.write_all(b"+").unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        client.release(token).unwrap();
+
+        let dup_fd_flags = unsafe {
+            libc::fcntl(
+                client
+                    .connection
+                    .transport
+                    .as_ref()
+                    .unwrap()
+                    .read
+                    .as_raw_fd(),
+                libc::F_GETFD,
+            )
+        };
+        assert_eq!(dup_fd_flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        drop(client);
+
+        // the originals are still open and usable
+        assert_ne!(
+            unsafe { libc::fcntl(file_read.as_raw_fd(), libc::F_GETFD) },
+            -1
+        );
+        assert_ne!(
+            unsafe { libc::fcntl(file_write.as_raw_fd(), libc::F_GETFD) },
+            -1
+        );
+        let mut token = [0; 1];
+        file_read.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[test]
+    fn test_acquire_tokens_partial() {
+        // one token, then the job server is closed
+        let (file_read, mut file_write) = pipe();
+        let (_other_read, other_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            other_write.as_raw_fd(),
+        ))
+        .unwrap();
+        file_write.write_all(b"+").unwrap();
+        drop(file_write);
+
+        let tokens = client.acquire_tokens(3).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].as_ref(), b"+");
+
+        // nothing acquired at all is an error
+        let err = client.acquire_tokens(3).unwrap_err();
+        assert_eq!(JobServerError::from_io(&err), Some(&JobServerError::Closed));
+    }
+
+    #[test]
+    fn test_acquire_tokens_lifo_release() {
+        let (mut file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"abc").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        drop(client.acquire_tokens(3).unwrap());
+        let mut released = [0; 3];
+        file_read.read_exact(&mut released).unwrap();
+        assert_eq!(&released, b"cba");
+    }
+
+    #[test]
+    fn test_acquire_cancellable() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        let client = Client::new(&style).unwrap();
+
+        let cancel = AtomicBool::new(false);
+        let start = Instant::now();
+        let token = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Release);
+            });
+            client.acquire_cancellable(&cancel).unwrap()
+        });
+        assert!(token.is_none());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        cancel.store(false, Ordering::Release);
+        file_write.write_all(b"+").unwrap();
+        let token = client.acquire_cancellable(&cancel).unwrap().unwrap();
+        assert_eq!(token.bytes(), b"+");
+    }
+
+    #[test]
+    fn test_acquire_closed() {
+        // the write end belongs to a different pipe, so the read end
+        // sees EOF once its own writer is gone
+        let (file_read, file_write) = pipe();
+        let (_other_read, other_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            other_write.as_raw_fd(),
+        ))
+        .unwrap();
+        drop(file_write);
+
+        let err = client.acquire().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(JobServerError::from_io(&err), Some(&JobServerError::Closed));
+    }
+
+    #[test]
+    fn test_acquire_or_serial() {
+        let (file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+        file_write.write_all(b"+").unwrap();
+
+        let token = client.acquire_or_serial().unwrap();
+        assert_eq!(token.as_ref().map(AsRef::as_ref), Some(&b"+"[..]));
+        assert!(client.acquire_or_serial().unwrap().is_none());
+
+        let (file_read, file_write) = pipe();
+        let (_other_read, other_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            other_write.as_raw_fd(),
+        ))
+        .unwrap();
+        drop(file_write);
+        let (token, logs) = capture_logs(|| client.acquire_or_serial());
+        assert!(token.unwrap().is_none());
+        assert!(logs.contains("jobserver closed"));
+    }
+
+    #[test]
+    fn test_pipe_fds_reused() {
+        let (file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+        file_write.write_all(b"+").unwrap();
+
+        // the numbers are free for a different pipe now
+        drop((file_read, file_write));
+        let (_other_read, mut other_write) = pipe();
+        other_write.write_all(b"x").unwrap();
+
+        assert_eq!(client.acquire().unwrap().as_ref(), b"+");
+    }
+
+    #[test]
+    fn test_ping() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"+").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        assert!(client.ping().unwrap() > Duration::ZERO);
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_acquire_with_policy() {
+        let mut fifo = TestFifo::new("policy", b"");
+        let style = JobServerStyle::Fifo(fifo.path());
+
+        let client = Client::builder()
+            .policy(AcquirePolicy::FailFast)
+            .build(&style)
+            .unwrap();
+        assert!(client.acquire_with_policy().unwrap().is_none());
+
+        let client = Client::builder()
+            .policy(AcquirePolicy::Timeout(Duration::from_millis(20)))
+            .build(&style)
+            .unwrap();
+        let start = Instant::now();
+        assert!(client.acquire_with_policy().unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // blocks until the token shows up
+        let client = Client::builder()
+            .policy(AcquirePolicy::Block)
+            .build(&style)
+            .unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                fifo.file.write_all(b"+").unwrap();
+            });
+            let token = client.acquire_with_policy().unwrap().unwrap();
+            assert_eq!(token.as_ref(), b"+");
+        });
+    }
+
+    #[test]
+    fn test_fifo_escaped_space() {
+        let fifo = TestFifo::new("escaped space", b"+");
+        let escaped = fifo.path().replace(' ', r"\ ");
+        let makeflags = format!(" -j2 --jobserver-auth=fifo:{escaped}");
+        let style = parse_jobserver_auth(&makeflags).unwrap();
+        assert_eq!(style, JobServerStyle::Fifo(&escaped));
+        assert_eq!(
+            JobServerStyleOwned::from(&style),
+            JobServerStyleOwned::Fifo(fifo.path.clone())
+        );
+
+        let client = Client::new(&style).unwrap();
+        assert_eq!(client.acquire().unwrap().bytes(), b"+");
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_from_makeflags() {
+        let fifo = TestFifo::new("from-makeflags", b"+");
+        let makeflags = format!(" -j2 --jobserver-auth=fifo:{}", fifo.path());
+
+        let client = Client::from_makeflags(&makeflags).unwrap().unwrap();
+        assert_eq!(
+            client.style(),
+            Some(&JobServerStyleOwned::Fifo(fifo.path.clone()))
+        );
+        assert_eq!(client.parallelism(), Some(2));
+        assert_eq!(client.acquire().unwrap().bytes(), b"+");
+
+        assert!(Client::from_makeflags(" ").unwrap().is_none());
+        assert!(Client::from_makeflags(" -j2 --jobserver-auth=fifo:").is_err());
+    }
+
+    #[test]
+    fn test_fifo_acquire_release() {
+        let mut fifo = TestFifo::new("client", b"+");
+
+        let client = Client::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        assert!(client.try_acquire().unwrap().is_none());
+        client.release(token).unwrap();
+
+        let mut token = [0; 1];
+        fifo.file.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[test]
+    fn test_acquire_tokens_implicit_token() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"abc").unwrap();
+
+        let client = Client::builder()
+            .implicit_token(true)
+            .build(&style)
+            .unwrap();
+        let tokens = client.acquire_tokens(3).unwrap();
+        let bytes: Vec<_> = tokens.iter().map(AsRef::as_ref).collect();
+        assert_eq!(bytes, [b"b", b"a"]);
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+        assert!(client.acquire_tokens(1).unwrap().is_empty());
+
+        let client = Client::new(&style).unwrap();
+        let tokens = client.acquire_tokens(1).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].as_ref(), b"c");
+    }
+
+    #[test]
+    fn test_descriptor_not_inherited() {
+        let err = Client::new(&JobServerStyle::Pipe(i32::MAX, i32::MAX)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("prefix the recipe command with `+` so Make passes the jobserver fds"));
+    }
+
+    #[test]
+    fn test_open_fifo() {
+        let fifo = TestFifo::new("open", b"");
+
+        // opening doesn't wait for a peer and leaves the fifo blocking
+        let (read, write) = super::open_fifo(&fifo.path, Duration::from_secs(5)).unwrap();
+        for (file, mode) in [(&read, libc::O_RDONLY), (&write, libc::O_WRONLY)] {
+            let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+            assert_eq!(flags & libc::O_NONBLOCK, 0);
+            assert_eq!(flags & libc::O_ACCMODE, mode);
+        }
+
+        assert!(
+            super::open_fifo(Path::new("/nonexistent/GMfifo1"), Duration::from_secs(5)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_open_fifo_without_peer() {
+        let path = mkfifo("no-peer");
+
+        let start = Instant::now();
+        let result = super::open_fifo(&path, Duration::from_secs(5));
+        fs::remove_file(&path).unwrap();
+        let (read, _write) = result.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let flags = unsafe { libc::fcntl(read.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+    }
+
+    #[test]
+    fn test_open_fifo_strategies() {
+        let fifo = TestFifo::new("open-strategies", b"");
+
+        let mut strategies = vec![super::open_fifo_split(&fifo.path).unwrap()];
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            strategies.push(super::open_fifo_read_write(&fifo.path).unwrap());
+        }
+        for (mut read, mut write) in strategies {
+            write.write_all(b"+").unwrap();
+            let mut token = [0; 1];
+            read.read_exact(&mut token).unwrap();
+            assert_eq!(&token, b"+");
+        }
+    }
+
+    #[test]
+    fn test_open_not_a_fifo() {
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-not-a-fifo", process::id()));
+        fs::write(&path, b"+").unwrap();
+
+        let err = super::open_fifo(&path, Duration::from_secs(5)).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            JobServerError::from_io(&err),
+            Some(&JobServerError::NotAFifo(
+                path.to_str().unwrap().to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_pipe_not_a_pipe() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let fd = socket.as_raw_fd();
+        let err = Client::new(&JobServerStyle::Pipe(fd, fd)).unwrap_err();
+        assert_eq!(
+            JobServerError::from_io(&err),
+            Some(&JobServerError::NotAPipe(fd, "socket"))
+        );
+
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-not-a-pipe", process::id()));
+        fs::write(&path, b"+").unwrap();
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (pipe_read, _pipe_write) = pipe();
+        let style = JobServerStyle::Pipe(pipe_read.as_raw_fd(), file.as_raw_fd());
+        let err = Client::new(&style).unwrap_err();
+        assert_eq!(
+            JobServerError::from_io(&err),
+            Some(&JobServerError::NotAPipe(file.as_raw_fd(), "regular file"))
+        );
+        assert!(err.to_string().contains("is a regular file, not a pipe"));
+    }
+
+    #[test]
+    fn test_serial_fallback() {
+        let client = Client::serial_fallback();
+        assert!(client.is_serial());
+
+        let token = client.acquire().unwrap();
+        assert!(token.as_ref().is_empty());
+        assert!(client.try_acquire().unwrap().is_none());
+        assert!(client
+            .acquire_backoff(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+        assert!(client.connection.release(b"+").is_err());
+
+        client.release(token).unwrap();
+        assert!(client.try_acquire().unwrap().unwrap().as_ref().is_empty());
+    }
+
+    #[test]
+    fn test_serial_fallback_acquire_tokens() {
+        let client = Client::serial_fallback();
+        // there's only the implicit token, asking for more returns it
+        // instead of waiting forever
+        let tokens = client.acquire_tokens(2).unwrap();
+        assert_eq!(tokens.len(), 1);
+
+        // a blocked acquire is woken by the release on another thread
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                drop(tokens);
+            });
+            assert!(client.acquire().unwrap().as_ref().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"++").unwrap();
+
+        let client = Client::new(&style).unwrap();
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                || {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    completed.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .collect();
+        client.run_parallel(tasks).unwrap();
+
+        assert_eq!(completed.load(Ordering::SeqCst), 8);
+        // two tokens from the job server and the implicit one
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_configure_command() {
+        let (file_read, file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        let mut client = Client::new(&style).unwrap();
+        client.job_count = Some(2);
+        let transport = client.connection.transport.as_ref().unwrap();
+        let (read_fd, write_fd) = (transport.read.as_raw_fd(), transport.write_fd());
+
+        // the child returns a token through the inherited write fd
+        let mut cmd = Command::new("sh");
+        cmd.args([
+            "-c",
+            &format!("printf %s \"$MAKEFLAGS\"; printf + > /dev/fd/{write_fd}"),
+        ]);
+        client.configure_command(&mut cmd);
+        let output = cmd.output().unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(" -j2 --jobserver-auth={read_fd},{write_fd}")
+        );
+        assert_eq!(client.try_acquire().unwrap().unwrap().as_ref(), b"+");
+        // our fds are still close-on-exec
+        let flags = unsafe { libc::fcntl(write_fd, libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        let fifo = TestFifo::new("configure-command", b"");
+        let client = Client::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+        let mut cmd = Command::new("sh");
+        client.configure_command(&mut cmd);
+        let output = cmd
+            .args(["-c", "printf %s \"$MAKEFLAGS\""])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(" --jobserver-auth=fifo:{}", fifo.path())
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let fifo = TestFifo::new("metrics", b"+");
+        let client = Client::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let token = client.acquire().unwrap();
+            client.release(token).unwrap();
+        });
+
+        let samples: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| key.key().name() == "jobserver_acquire_wait_seconds")
+            .collect();
+        assert_eq!(samples.len(), 1);
+        let (key, _, _, value) = &samples[0];
+        assert!(key
+            .key()
+            .labels()
+            .any(|label| label.key() == "style" && label.value() == "fifo"));
+        assert!(matches!(value, DebugValue::Histogram(values) if values.len() == 1));
+    }
+
+    #[test]
+    fn test_makeflags() {
+        let (file_read, file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+        let transport = client.connection.transport.as_ref().unwrap();
+        // our duplicates, not the inherited fds
+        assert_eq!(
+            parse_jobserver_auth(client.makeflags().to_str().unwrap()),
+            Ok(JobServerStyle::Pipe(
+                transport.read.as_raw_fd(),
+                transport.write_fd()
+            ))
+        );
+
+        let fifo = TestFifo::new("makeflags", b"");
+        let client = Client::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+        assert_eq!(
+            parse_jobserver_auth_bytes(client.makeflags().as_bytes()).as_ref(),
+            Ok(client.style().unwrap())
+        );
+
+        // the space is escaped, the child parses the whole path
+        let fifo = TestFifo::new("makeflags with space", b"");
+        let client = Client::new(&JobServerStyle::Fifo(&fifo.path().replace(' ', r"\ "))).unwrap();
+        assert_eq!(
+            client.style(),
+            Some(&JobServerStyleOwned::Fifo(fifo.path.clone()))
+        );
+        assert_eq!(
+            parse_jobserver_auth_bytes(client.makeflags().as_bytes()).as_ref(),
+            Ok(client.style().unwrap())
+        );
+
+        assert_eq!(Client::serial_fallback().makeflags(), "");
+    }
+
+    #[test]
+    fn test_release_nonblocking() {
+        let (mut file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+
+        // a full pipe, left non-blocking
+        super::set_nonblocking(file_write.as_raw_fd(), true).unwrap();
+        while file_write.write(&[b'+'; 4096]).is_ok() {}
+
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let mut buf = [0; 4096];
+            file_read.read_exact(&mut buf).unwrap();
+        });
+        client.connection.release(b"+").unwrap();
+        reader.join().unwrap();
+
+        assert!(super::set_nonblocking(file_write.as_raw_fd(), true).unwrap());
+    }
+
+    #[test]
+    fn test_try_acquire_release_full_pipe() {
+        let (mut file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+        file_write.write_all(b"+").unwrap();
+        let token = client.try_acquire().unwrap().unwrap();
+
+        // all tokens are out, the pipe is full nevertheless
+        super::set_nonblocking(file_write.as_raw_fd(), true).unwrap();
+        let mut filled = 0;
+        while let Ok(n) = file_write.write(&[b'-'; 4096]) {
+            filled += n;
+        }
+        super::set_nonblocking(file_write.as_raw_fd(), false).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(token.release()).unwrap());
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("non-blocking release hung")
+            .unwrap();
+        assert_eq!(*client.connection.pending.lock().unwrap(), b"+");
+
+        super::set_nonblocking(file_read.as_raw_fd(), true).unwrap();
+        let mut buf = vec![0; filled + 1];
+        assert_eq!(file_read.read(&mut buf).unwrap(), filled);
+        super::set_nonblocking(file_read.as_raw_fd(), false).unwrap();
+
+        // the queued release is flushed on drop
+        drop(client);
+        let mut token = [0];
+        file_read.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[test]
+    fn test_style_and_parallelism() {
+        let (file_read, file_write) = pipe();
+        let (read_fd, write_fd) = (file_read.as_raw_fd(), file_write.as_raw_fd());
+        let client = Client::builder()
+            .from_makeflags(&format!(" -j4 --jobserver-auth={read_fd},{write_fd}"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.style(),
+            Some(&JobServerStyleOwned::Pipe(read_fd, write_fd))
+        );
+        assert_eq!(client.parallelism(), Some(4));
+        assert_eq!(client.initial_token_estimate(), Some(3));
+
+        let fifo = TestFifo::new("style", b"");
+        let makeflags = format!(" --jobserver-auth=fifo:{}", fifo.path());
+        let client = Client::builder()
+            .from_makeflags(&makeflags)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.style(),
+            Some(&JobServerStyleOwned::Fifo(fifo.path().into()))
+        );
+        assert_eq!(client.parallelism(), None);
+
+        // a bare -j doesn't limit the jobs
+        let client = Client::builder()
+            .from_makeflags(&format!(" -j --jobserver-auth={read_fd},{write_fd}"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.parallelism(), None);
+        assert_eq!(client.initial_token_estimate(), None);
+
+        // -j1 runs serially, but still knows its parallelism
+        let client = Client::builder()
+            .from_makeflags(&format!(" -j1 --jobserver-auth={read_fd},{write_fd}"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.style(), None);
+        assert_eq!(client.parallelism(), Some(1));
+        assert_eq!(client.initial_token_estimate(), Some(0));
+
+        let client = Client::serial_fallback();
+        assert_eq!(client.style(), None);
+        assert_eq!(client.parallelism(), None);
+    }
+
+    #[test]
+    fn test_trait_object() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"+").unwrap();
+        let clients: [Box<dyn JobServerClient>; 2] = [
+            Box::new(Client::new(&style).unwrap()),
+            Box::new(Client::serial_fallback()),
+        ];
+
+        for client in &clients {
+            let token = client.acquire().unwrap();
+            assert!(client.try_acquire().unwrap().is_none());
+            client.release(token).unwrap();
+            drop(client.try_acquire().unwrap().unwrap());
+        }
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_release_outstanding() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"++").unwrap();
+        let client: Box<dyn JobServerClient> = Box::new(Client::new(&style).unwrap());
+
+        let first = client.acquire().unwrap();
+        let second = client.try_acquire().unwrap().unwrap();
+        assert_eq!(client.release_outstanding().unwrap(), 2);
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+
+        // not released twice
+        drop(first);
+        client.release(second).unwrap();
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+        assert_eq!(client.release_outstanding().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_release_partial_write() {
+        const TOKEN_SIZE: usize = 256 * 1024;
+
+        let (mut file_read, file_write) = small_pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+        let token = vec![b'+'; TOKEN_SIZE];
+
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; TOKEN_SIZE];
+            file_read.read_exact(&mut buf).unwrap();
+            (file_read, buf)
+        });
+        client.connection.release(&token).unwrap();
+        let (mut file_read, buf) = reader.join().unwrap();
+        assert_eq!(buf, token);
+
+        // the bytes the pipe doesn't take are queued and flushed on drop
+        client.connection.release_nonblocking(&token).unwrap();
+        let queued = client.connection.pending.lock().unwrap().len();
+        assert!(queued > 0 && queued < TOKEN_SIZE);
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; TOKEN_SIZE];
+            file_read.read_exact(&mut buf).unwrap();
+            buf
+        });
+        drop(client);
+        assert_eq!(reader.join().unwrap(), token);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let fifo = TestFifo::new("reconnect", b"+");
+        let makeflags = format!(" -j2 --jobserver-auth=fifo:{}", fifo.path());
+        let mut client = Client::builder()
+            .from_makeflags(&makeflags)
+            .unwrap()
+            .unwrap();
+        let old_token = client.acquire().unwrap();
+
+        // Make tears the fifo down and recreates it
+        drop(fifo);
+        let fifo = TestFifo::new("reconnect", b"++");
+        assert!(client.try_acquire().unwrap().is_none());
+
+        client
+            .reconnect_to(&makeflags.replace("-j2", "-j3"))
+            .unwrap();
+        assert_eq!(client.parallelism(), Some(3));
+        let token = client.try_acquire().unwrap().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        drop(old_token);
+        drop(token);
+        let style = JobServerStyle::Fifo(fifo.path());
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+
+        // a failed reconnect keeps the connection
+        assert!(client.reconnect_to(" -j2").is_err());
+        drop(client.try_acquire().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_reconnect_options() {
+        let fifo = TestFifo::new("reconnect-options", b"");
+        let makeflags = format!(" -j2 --jobserver-auth=fifo:{}", fifo.path());
+        let mut client = Client::builder()
+            .token_size(2)
+            .policy(AcquirePolicy::FailFast)
+            .from_makeflags(&makeflags)
+            .unwrap()
+            .unwrap();
+
+        // rebuilt with the options of the builder
+        drop(fifo);
+        let mut fifo = TestFifo::new("reconnect-options", b"");
+        client.reconnect_to(&makeflags).unwrap();
+        assert!(client.acquire_with_policy().unwrap().is_none());
+        fifo.file.write_all(b"ab").unwrap();
+        assert_eq!(
+            client.acquire_with_policy().unwrap().unwrap().bytes(),
+            b"ab"
+        );
+
+        // and with the same rules, -j1 runs serially
+        client
+            .reconnect_to(&makeflags.replace("-j2", "-j1"))
+            .unwrap();
+        assert!(client.is_serial());
+        assert!(client.reconnect_to("").is_err());
+        assert!(client.is_serial());
+    }
+
+    #[test]
+    fn test_release_all() {
+        let fifo = TestFifo::new("release-all", b"+++");
+        let style = JobServerStyle::Fifo(fifo.path());
+        let client = Client::new(&style).unwrap();
+
+        let tokens = client.acquire_tokens(3).unwrap();
+        assert_eq!(available_token_estimate(&style).unwrap(), 0);
+        client.release_all(tokens).unwrap();
+        assert_eq!(available_token_estimate(&style).unwrap(), 3);
+
+        // the write handle of broken is the read end of the pipe, its
+        // failing releases don't prevent the release of the fifo token
+        let (file_read, mut file_write) = pipe();
+        file_write.write_all(b"++").unwrap();
+        let read_fd = file_read.as_raw_fd();
+        let broken = Client::new(&JobServerStyle::Pipe(read_fd, read_fd)).unwrap();
+        let tokens = vec![
+            broken.acquire().unwrap(),
+            client.acquire().unwrap(),
+            broken.acquire().unwrap(),
+        ];
+        let err = client.release_all(tokens).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("releasing 2 of 3 tokens failed"));
+        assert_eq!(available_token_estimate(&style).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let (file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+
+        assert!(client.try_acquire().unwrap().is_none());
+        file_write.write_all(b"+").unwrap();
+        let token = client.try_acquire().unwrap().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        assert!(client.try_acquire().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_try_acquire_own_description() {
+        let (file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+
+        // a reader of the shared description keeps blocking while we
+        // probe
+        let mut shared = file_read.try_clone().unwrap();
+        let reader = thread::spawn(move || {
+            let mut token = [0];
+            shared.read_exact(&mut token).map(|()| token)
+        });
+        for _ in 0..100 {
+            assert!(client.try_acquire().unwrap().is_none());
+        }
+        let flags = unsafe { libc::fcntl(file_read.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+        file_write.write_all(b"+").unwrap();
+        assert_eq!(&reader.join().unwrap().unwrap(), b"+");
+    }
+
+    #[test]
+    fn test_try_acquire_partial_token() {
+        // the job server is closed in the middle of a token
+        let (file_read, mut file_write) = pipe();
+        let (mut other_read, other_write) = pipe();
+        let client = Client::builder()
+            .token_size(2)
+            .build(&JobServerStyle::Pipe(
+                file_read.as_raw_fd(),
+                other_write.as_raw_fd(),
+            ))
+            .unwrap();
+        file_write.write_all(b"+").unwrap();
+        drop(file_write);
+
+        let err = client.try_acquire().unwrap_err();
+        assert_eq!(
+            JobServerError::from_io(&err),
+            Some(&JobServerError::TruncatedToken(1, 2))
+        );
+        // the first byte was written back
+        let mut token = [0];
+        other_read.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[test]
+    fn test_drain() {
+        let fifo = TestFifo::new("drain", b"+++");
+        let client = Client::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+
+        let tokens = client.drain().unwrap();
+        assert_eq!(tokens.len(), 3);
+        assert!(client.try_acquire().unwrap().is_none());
+
+        client.release_all(tokens).unwrap();
+        assert_eq!(client.drain().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_drain_and_count() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"+++++").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        assert_eq!(client.drain_and_count().unwrap(), 5);
+        assert_eq!(available_token_estimate(&style).unwrap(), 5);
+
+        assert_eq!(Client::serial_fallback().drain_and_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_acquire_backoff() {
+        let (file_read, mut file_write) = pipe();
+        let client = Client::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            file_write.as_raw_fd(),
+        ))
+        .unwrap();
+
+        assert!(client
+            .acquire_backoff(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            file_write.write_all(b"+").unwrap();
+            file_write
+        });
+        assert_eq!(
+            client
+                .acquire_backoff(Duration::from_secs(5))
+                .unwrap()
+                .unwrap()
+                .as_ref(),
+            b"+"
+        );
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn test_same_read_write_fd() {
+        let mut fifo = bidirectional_fifo("same-fd");
+        let fd = fifo.as_raw_fd();
+
+        let (client, logs) = capture_logs(|| Client::new(&JobServerStyle::Pipe(fd, fd)));
+        let client = client.unwrap();
+        assert!(logs.contains("same fd for reading and writing"));
+        fifo.write_all(b"+").unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.as_ref(), b"+");
+        client.release(token).unwrap();
+        drop(client);
+
+        // no double close, the original is still open and has the token
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+        let mut token = [0; 1];
+        fifo.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+}
@@ -0,0 +1,1759 @@
+//! Parsing of the GNU job server configuration found in `MAKEFLAGS`
+//! and helpers to talk to the job server it describes.
+
+#![warn(rust_2018_idioms)]
+#![warn(clippy::pedantic)]
+
+use std::{env, fmt, io};
+#[cfg(target_family = "unix")]
+use std::{
+    fs::File,
+    ops::Range,
+    os::fd::{AsRawFd, RawFd},
+    path::PathBuf,
+};
+
+#[cfg(target_family = "unix")]
+use anyhow::Context;
+use thiserror::Error;
+
+#[cfg(all(feature = "async-io", target_family = "unix"))]
+pub mod async_io_client;
+#[cfg(target_family = "unix")]
+mod audit;
+#[cfg(target_family = "unix")]
+mod builder;
+#[cfg(target_family = "unix")]
+mod client;
+#[cfg(target_family = "unix")]
+mod semaphore;
+#[cfg(all(feature = "server", target_family = "unix"))]
+pub mod server;
+#[cfg(all(test, target_family = "unix"))]
+mod test_util;
+#[cfg(target_family = "unix")]
+mod throttle;
+#[cfg(target_family = "unix")]
+mod token;
+#[cfg(all(feature = "tokio", target_family = "unix"))]
+pub mod tokio_client;
+mod transport;
+
+#[cfg(target_family = "unix")]
+pub use audit::{JsonlAudit, TokenAudit, TraceAudit};
+#[cfg(target_family = "unix")]
+pub use builder::{AcquirePolicy, ClientBuilder, WaitStrategy};
+#[cfg(target_family = "unix")]
+pub use client::{Client, JobServerClient};
+#[cfg(target_family = "unix")]
+pub use semaphore::{JobServerSemaphore, Permit};
+#[cfg(target_family = "unix")]
+pub use throttle::{throttle, Throttle, Throttled};
+#[cfg(target_family = "unix")]
+pub use token::Token;
+pub use transport::{acquire_token, release_token, TokenTransport};
+#[cfg(target_os = "windows")]
+pub use transport::{HandleTransport, SemaphoreTransport};
+
+/// The job server style as advertised by `--jobserver-auth=` in
+/// `MAKEFLAGS`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum JobServerStyle<'a> {
+    #[cfg(target_family = "unix")]
+    /// The Fifo job server style is supported since Make 4.4 and is a FIFO/named pipe.
+    Fifo(&'a str),
+    /// Pipe is the older implementation, supported since ages. It
+    /// consists of two file descriptors, the first one is for reading
+    /// the second one for writing. Make always passes the two distinct
+    /// ends of a pipe, the same fd for both means a released token can
+    /// be read back immediately by the same client. On Windows, some
+    /// ports of Make pass an anonymous pipe instead of a semaphore, the
+    /// numbers are the values of the two Win32 `HANDLE`s then.
+    Pipe(i32, i32),
+    #[cfg(target_os = "windows")]
+    /// Sem is for Win32 semaphore, the auth is its name, it's opened
+    /// with [`SemaphoreTransport::open`].
+    Sem(&'a str),
+}
+
+/// The owned counterpart of [`JobServerStyle`], the FIFO path may be
+/// any bytes, not only UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum JobServerStyleOwned {
+    #[cfg(target_family = "unix")]
+    /// See [`JobServerStyle::Fifo`].
+    Fifo(PathBuf),
+    /// See [`JobServerStyle::Pipe`].
+    Pipe(i32, i32),
+    #[cfg(target_os = "windows")]
+    /// See [`JobServerStyle::Sem`].
+    Sem(String),
+}
+
+/// The backslashes escaping spaces and backslashes in a FIFO path, as
+/// borrowed from `MAKEFLAGS`, are removed, the owned path is the one
+/// on the filesystem.
+impl From<&JobServerStyle<'_>> for JobServerStyleOwned {
+    fn from(style: &JobServerStyle<'_>) -> Self {
+        match style {
+            #[cfg(target_family = "unix")]
+            JobServerStyle::Fifo(fifo_file) => {
+                JobServerStyleOwned::Fifo(PathBuf::from(unescape_flag_value(fifo_file)))
+            }
+            JobServerStyle::Pipe(read_fd, write_fd) => {
+                JobServerStyleOwned::Pipe(*read_fd, *write_fd)
+            }
+            #[cfg(target_os = "windows")]
+            JobServerStyle::Sem(name) => JobServerStyleOwned::Sem((*name).to_string()),
+        }
+    }
+}
+
+/// Formats the value of `--jobserver-auth=`, e.g. `fifo:/tmp/GMfifo`
+/// or `3,4`, the inverse of [`parse_jobserver_auth`].
+impl fmt::Display for JobServerStyle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(target_family = "unix")]
+            JobServerStyle::Fifo(fifo_file) => write!(f, "fifo:{fifo_file}"),
+            JobServerStyle::Pipe(read_fd, write_fd) => write!(f, "{read_fd},{write_fd}"),
+            #[cfg(target_os = "windows")]
+            JobServerStyle::Sem(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Errors while parsing the job server auth from `MAKEFLAGS`.
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseJobserverAuthError {
+    #[error("invalid jobserver auth \"{0}\"")]
+    InvalidJobServerAuth(String),
+    #[error("invalid pipe descriptors")]
+    InvalidPipeDescriptors,
+    #[error("empty fifo path")]
+    EmptyFifoPath,
+    /// Make always advertises an absolute FIFO path, a relative one is
+    /// corrupt or injected and would be opened in the current
+    /// directory.
+    #[error("fifo path \"{0}\" is not absolute")]
+    RelativeFifoPath(String),
+    /// The auth contradicts `--jobserver-style=`, `MAKEFLAGS` was most
+    /// likely partially overwritten.
+    #[error("jobserver auth contradicts style hint \"{0}\"")]
+    MismatchedStyleHint(String),
+}
+
+/// Errors talking to the job server.
+#[derive(Error, Debug, PartialEq)]
+pub enum JobServerError {
+    /// The usual cause for the "jobserver unavailable: using -j1"
+    /// warning of Make.
+    #[error("jobserver fd {0} is not inherited, prefix the recipe command with `+` so Make passes the jobserver fds")]
+    DescriptorNotInherited(i32),
+    /// Opening the FIFO hangs, e.g. on a stuck network filesystem.
+    #[error("opening jobserver fifo {0} timed out")]
+    OpenTimeout(String),
+    /// The FIFO path points to something else, e.g. a stale regular
+    /// file, reading tokens from it would hang or fail.
+    #[error("jobserver fifo {0} is not a fifo")]
+    NotAFifo(String),
+    /// A pipe descriptor refers to something else, e.g. a socket or a
+    /// regular file of a misconfigured wrapper, reading tokens from it
+    /// would hang or fail cryptically. The second field names what it
+    /// is instead.
+    #[error("jobserver fd {0} is a {1}, not a pipe")]
+    NotAPipe(i32, &'static str),
+    /// Every writer of the job server is gone, e.g. Make exited, no
+    /// token will ever become available. The [`io::Error`] keeps the
+    /// kind [`io::ErrorKind::UnexpectedEof`].
+    #[error("jobserver closed")]
+    Closed,
+    /// The job server was closed in the middle of a token, the fields
+    /// are the bytes read and the token size. The partial token is
+    /// lost. The [`io::Error`] keeps the kind
+    /// [`io::ErrorKind::UnexpectedEof`].
+    #[error("jobserver closed after {0} of {1} token bytes")]
+    TruncatedToken(usize, usize),
+}
+
+impl JobServerError {
+    /// The job server error wrapped in an [`io::Error`], if any.
+    #[must_use]
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+/// Parses the job server auth from `MAKEFLAGS`, the last
+/// `--jobserver-auth=` wins. Only without one, the auth is also taken
+/// from the word after a standalone `--jobserver-auth`, as left by
+/// shells which split the flag. A pair of quotes around the value, as
+/// added by some wrappers, is stripped.
+///
+/// Make puts the single-letter flags first, without a dash, e.g. `ks
+/// -j2 --jobserver-auth=3,4` for `-k -s`, or a leading space if there
+/// are none. Only the long flags are matched, so `MAKEFLAGS` may also
+/// start with `--jobserver-auth=` right away.
+///
+/// A FIFO path with spaces escaped by a backslash is borrowed verbatim,
+/// with the backslashes, [`parse_jobserver_auth_bytes`] unescapes it.
+///
+/// # Errors
+///
+/// Fails if there's no job server auth, the FIFO path is empty or not
+/// absolute, or the pipe descriptors are malformed.
+#[cfg(target_family = "unix")]
+pub fn parse_jobserver_auth(
+    makeflags: &str,
+) -> Result<JobServerStyle<'_>, ParseJobserverAuthError> {
+    parse_jobserver_auth_spanned(makeflags).map(|(style, _)| style)
+}
+
+/// Parses the job server auth like [`parse_jobserver_auth`], and also
+/// returns the byte range of the `--jobserver-auth=...` flag in
+/// `makeflags` which produced it, e.g. to underline it in diagnostics.
+///
+/// If there's a FIFO and a pipe auth, the style hint
+/// `--jobserver-style=` picks the last one of its style, without a hint
+/// the last auth wins all the same. If `makeflags` advertises more
+/// than one distinct job server, e.g. leaked by nested Makes, a warning
+/// names all of them and the chosen one.
+///
+/// # Errors
+///
+/// Fails like [`parse_jobserver_auth`], or if the auth contradicts the
+/// style hint.
+#[cfg(target_family = "unix")]
+pub fn parse_jobserver_auth_spanned(
+    makeflags: &str,
+) -> Result<(JobServerStyle<'_>, Range<usize>), ParseJobserverAuthError> {
+    let hint = parse_jobserver_style_hint(makeflags);
+    let flags = flags_only(makeflags);
+    let find = |flag| {
+        let auth = match (find_fifo_auth(flags, flag), find_pipe_auth(flags, flag)) {
+            (Some(fifo), Some(pipe)) => Some(match hint {
+                Some("fifo") => fifo,
+                Some("pipe") => pipe,
+                // the last auth wins, even if it's invalid
+                _ => {
+                    if pipe.0 > fifo.0 {
+                        pipe
+                    } else {
+                        fifo
+                    }
+                }
+            }),
+            (fifo, pipe) => fifo.or(pipe),
+        };
+        auth.map(|(_, auth)| auth)
+    };
+    // some shells mangle the auth into `--jobserver-auth fifo:/tmp/x`,
+    // the value is the next word then, the form with `=` wins
+    let (style, span) = find(AUTH_FLAG)
+        .or_else(|| find(AUTH_FLAG_SPACED))
+        .ok_or_else(|| ParseJobserverAuthError::InvalidJobServerAuth(makeflags.to_string()))??;
+
+    if let Some(hint) = hint {
+        if !style_matches_hint(&style, hint) {
+            return Err(ParseJobserverAuthError::MismatchedStyleHint(
+                hint.to_string(),
+            ));
+        }
+    }
+
+    let mut advertised = parse_all_jobserver_auth(makeflags);
+    advertised.dedup();
+    if advertised.len() > 1 {
+        let advertised: Vec<_> = advertised.iter().map(ToString::to_string).collect();
+        tracing::warn!(
+            advertised = advertised.join(" "),
+            chosen = %style,
+            "more than one jobserver advertised in MAKEFLAGS"
+        );
+    }
+
+    Ok((style, span))
+}
+
+/// Parses every `--jobserver-auth=` in `makeflags` in order, skipping
+/// malformed ones. Nested Makes may leak more than one, while only the
+/// one chosen by [`parse_jobserver_auth`] is meant for us.
+#[cfg(target_family = "unix")]
+#[must_use]
+pub fn parse_all_jobserver_auth(makeflags: &str) -> Vec<JobServerStyle<'_>> {
+    flags_only(makeflags)
+        .match_indices("--jobserver-auth=")
+        .filter(|&(pos, _)| at_flag_boundary(makeflags, pos))
+        .filter_map(|(pos, flag)| {
+            let pos_eq = pos + flag.len();
+            let flag = &makeflags[pos..(pos_eq + flag_value(makeflags, pos_eq).len())];
+            let (_, auth) =
+                find_fifo_auth(flag, AUTH_FLAG).or_else(|| find_pipe_auth(flag, AUTH_FLAG))?;
+            let (style, _) = auth.ok()?;
+            Some(style)
+        })
+        .collect()
+}
+
+// The flags of `makeflags`, a standalone `--` ends them, everything
+// after it are goals or variables, e.g. `-j2 -- --jobserver-auth=x`
+// makes a target of the auth. A leading `--` before any flag is only
+// an option terminator, the flags follow it. Searching in a prefix
+// keeps the offsets valid for `makeflags`.
+fn flags_only(makeflags: &str) -> &str {
+    let mut pos = 0;
+    let mut escaped = false;
+    let mut leading = true;
+    for word in makeflags.split(|c: char| c.is_ascii_whitespace()) {
+        if word == "--" && !escaped && !leading {
+            return &makeflags[..pos];
+        }
+        leading &= word.is_empty() || word == "--";
+        // an escaped space continues the word
+        escaped = (word.len() - word.trim_end_matches('\\').len()) % 2 == 1;
+        pos += word.len() + 1;
+    }
+    makeflags
+}
+
+// The value of the flag starting at `pos_eq`, up to the next space,
+// or newline if MAKEFLAGS was captured from a log. Only ASCII
+// whitespace ends it, the raw bytes of a path are mapped to Latin-1,
+// where e.g. 0xA0 is a no-break space.
+// Offsets are only ever derived from matches of ASCII patterns, so
+// slicing stays on char boundaries for non-ASCII paths too.
+// Make escapes spaces within a flag with a backslash, they don't end
+// the value.
+fn flag_value(makeflags: &str, pos_eq: usize) -> &str {
+    let value = &makeflags[pos_eq..];
+    let mut escaped = false;
+    let end = value
+        .char_indices()
+        .find(|&(_, c)| {
+            let end = c.is_ascii_whitespace() && !escaped;
+            escaped = c == '\\' && !escaped;
+            end
+        })
+        .map_or(value.len(), |(space_pos, _)| space_pos);
+    &value[..end]
+}
+
+// Whether a flag matched at `pos` starts a word, so e.g. the auth
+// isn't found within `--not-jobserver-auth=x`.
+fn at_flag_boundary(makeflags: &str, pos: usize) -> bool {
+    makeflags[..pos]
+        .chars()
+        .next_back()
+        .is_none_or(|c| c.is_ascii_whitespace())
+}
+
+// The position of the last `flag` in `makeflags` which starts a word.
+fn rfind_flag(makeflags: &str, flag: &str) -> Option<usize> {
+    makeflags
+        .rmatch_indices(flag)
+        .map(|(pos, _)| pos)
+        .find(|&pos| at_flag_boundary(makeflags, pos))
+}
+
+// Removes the backslashes Make escapes spaces and backslashes with.
+#[cfg(target_family = "unix")]
+pub(crate) fn unescape_flag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ (' ' | '\\'))) => {
+                unescaped.push(next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+const AUTH_FLAG: &str = "--jobserver-auth=";
+// the auth mangled by a shell, separated from the flag by a space
+const AUTH_FLAG_SPACED: &str = "--jobserver-auth ";
+
+// The auth of the last flag of a style with the span of the flag, the
+// finders return it next to the flag's position, even if it's invalid.
+#[cfg(target_family = "unix")]
+type FoundAuth<'a> = Result<(JobServerStyle<'a>, Range<usize>), ParseJobserverAuthError>;
+
+#[cfg(target_family = "unix")]
+fn find_fifo_auth<'a>(makeflags: &'a str, flag: &str) -> Option<(usize, FoundAuth<'a>)> {
+    // quick and dirty implementation, don't look too closely!
+
+    let (pos, value) = makeflags
+        .rmatch_indices(flag)
+        .filter(|&(pos, _)| at_flag_boundary(makeflags, pos))
+        .map(|(pos, flag)| (pos, flag_value(makeflags, pos + flag.len())))
+        .find(|(_, value)| unquote(value).starts_with("fifo:"))?;
+    let fifo_file = &unquote(value)["fifo:".len()..];
+
+    if fifo_file.is_empty() {
+        return Some((pos, Err(ParseJobserverAuthError::EmptyFifoPath)));
+    }
+    let span = pos..(pos + flag.len() + value.len());
+
+    // some makes emit fifo://tmp/x, repeated leading slashes are
+    // the same as a single one, which has to stay
+    let slashes = fifo_file.len() - fifo_file.trim_start_matches('/').len();
+    let fifo_file = &fifo_file[slashes.saturating_sub(1)..];
+    if !fifo_file.starts_with('/') {
+        return Some((
+            pos,
+            Err(ParseJobserverAuthError::RelativeFifoPath(
+                fifo_file.to_string(),
+            )),
+        ));
+    }
+    Some((pos, Ok((JobServerStyle::Fifo(fifo_file), span))))
+}
+
+#[cfg(target_family = "unix")]
+fn find_pipe_auth<'a>(makeflags: &'a str, flag: &str) -> Option<(usize, FoundAuth<'a>)> {
+    let (pos, quoted) = makeflags
+        .rmatch_indices(flag)
+        .filter(|&(pos, _)| at_flag_boundary(makeflags, pos))
+        .map(|(pos, flag)| (pos, flag_value(makeflags, pos + flag.len())))
+        .find(|(_, value)| !unquote(value).starts_with("fifo:"))?;
+    let value = unquote(quoted);
+    // a pipe auth starts with a descriptor, only the value itself is
+    // checked, not the flags after it
+    if !value.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        return None;
+    }
+
+    let Some((Ok(read_fd), Ok(write_fd))) = value
+        .split_once(',')
+        .map(|(read_fd, write_fd)| (read_fd.parse(), write_fd.parse()))
+    else {
+        return Some((pos, Err(ParseJobserverAuthError::InvalidPipeDescriptors)));
+    };
+
+    Some((
+        pos,
+        Ok((
+            JobServerStyle::Pipe(read_fd, write_fd),
+            pos..(pos + flag.len() + quoted.len()),
+        )),
+    ))
+}
+
+// The value without a pair of quotes around it. Make never quotes it,
+// but some wrappers do, e.g. `--jobserver-auth="fifo:/tmp/x"`.
+fn unquote(value: &str) -> &str {
+    ['"', '\'']
+        .into_iter()
+        .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote))
+        .unwrap_or(value)
+}
+
+/// The generation of the job server protocol advertised in `MAKEFLAGS`,
+/// for diagnostics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobserverProtocol {
+    /// A pipe advertised by `--jobserver-fds=`, before Make 4.2.
+    LegacyPipe,
+    /// A pipe advertised by `--jobserver-auth=`, since Make 4.2.
+    Pipe,
+    /// A FIFO advertised by `--jobserver-auth=fifo:`, since Make 4.4.
+    Fifo,
+    /// A Win32 semaphore, advertised by its name.
+    Sem,
+}
+
+impl fmt::Display for JobserverProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobserverProtocol::LegacyPipe => "legacy-pipe",
+            JobserverProtocol::Pipe => "pipe",
+            JobserverProtocol::Fifo => "fifo",
+            JobserverProtocol::Sem => "sem",
+        })
+    }
+}
+
+/// Classifies the job server protocol advertised in `MAKEFLAGS`, the
+/// last `--jobserver-auth=` or `--jobserver-fds=` wins. It only looks
+/// at the shape of the value, the auth may still be malformed.
+#[must_use]
+pub fn classify_jobserver_protocol(makeflags: &str) -> Option<JobserverProtocol> {
+    let makeflags = flags_only(makeflags);
+    let (pos, flag) = ["--jobserver-auth=", "--jobserver-fds="]
+        .into_iter()
+        .filter_map(|flag| Some((rfind_flag(makeflags, flag)?, flag)))
+        .max()?;
+    let value = unquote(flag_value(makeflags, pos + flag.len()));
+
+    let is_pipe = value.split_once(',').is_some_and(|(read_fd, write_fd)| {
+        read_fd.parse::<i32>().is_ok() && write_fd.parse::<i32>().is_ok()
+    });
+    match (flag, value) {
+        (_, "") => None,
+        ("--jobserver-fds=", _) => Some(JobserverProtocol::LegacyPipe),
+        (_, value) if value.starts_with("fifo:") => Some(JobserverProtocol::Fifo),
+        _ if is_pipe => Some(JobserverProtocol::Pipe),
+        _ => Some(JobserverProtocol::Sem),
+    }
+}
+
+/// Classifies the job server protocol a Make supports by its feature
+/// list, the value of `.FEATURES`, e.g. exported as
+/// [`MAKE_FEATURES_VAR`]. Since Make 4.4 `jobserver-fifo` is listed
+/// besides `jobserver`, older ones only support the pipe.
+#[must_use]
+pub fn jobserver_feature(features: &str) -> Option<JobserverProtocol> {
+    let mut features = features.split_whitespace();
+    if features.clone().any(|feature| feature == "jobserver-fifo") {
+        Some(JobserverProtocol::Fifo)
+    } else if features.any(|feature| feature == "jobserver") {
+        Some(JobserverProtocol::Pipe)
+    } else {
+        None
+    }
+}
+
+/// The environment variable with the `.FEATURES` of Make, a Makefile
+/// has to export it explicitly, e.g. `export MAKE_FEATURES :=
+/// $(.FEATURES)`. See [`jobserver_feature`].
+pub const MAKE_FEATURES_VAR: &str = "MAKE_FEATURES";
+
+/// Parses the job server auth like [`parse_jobserver_auth`] from raw
+/// `MAKEFLAGS` bytes, e.g. from [`env::var_os`], so a FIFO path which
+/// isn't UTF-8 survives. The FIFO path is owned, so spaces escaped
+/// with a backslash are unescaped, e.g. `fifo:/tmp/a\ b/GMfifo` is the
+/// path `/tmp/a b/GMfifo`.
+///
+/// # Errors
+///
+/// Fails like [`parse_jobserver_auth`].
+#[cfg(target_family = "unix")]
+pub fn parse_jobserver_auth_bytes(
+    makeflags: &[u8],
+) -> Result<JobServerStyleOwned, ParseJobserverAuthError> {
+    use std::os::unix::ffi::OsStringExt;
+
+    // every byte maps to the char of the same value (Latin-1), the
+    // parser only matches ASCII, so the bytes come back unchanged
+    let latin1: String = makeflags.iter().copied().map(char::from).collect();
+    // every char came from a byte, none is dropped
+    let to_bytes =
+        |s: &str| -> Vec<u8> { s.chars().filter_map(|c| u8::try_from(c).ok()).collect() };
+
+    match parse_jobserver_auth(&latin1) {
+        Ok(JobServerStyle::Fifo(fifo_file)) => Ok(JobServerStyleOwned::Fifo(PathBuf::from(
+            std::ffi::OsString::from_vec(to_bytes(&unescape_flag_value(fifo_file))),
+        ))),
+        Ok(JobServerStyle::Pipe(read_fd, write_fd)) => {
+            Ok(JobServerStyleOwned::Pipe(read_fd, write_fd))
+        }
+        Err(ParseJobserverAuthError::InvalidJobServerAuth(_)) => {
+            Err(ParseJobserverAuthError::InvalidJobServerAuth(
+                String::from_utf8_lossy(makeflags).into_owned(),
+            ))
+        }
+        Err(ParseJobserverAuthError::MismatchedStyleHint(hint)) => {
+            Err(ParseJobserverAuthError::MismatchedStyleHint(
+                String::from_utf8_lossy(&to_bytes(&hint)).into_owned(),
+            ))
+        }
+        Err(ParseJobserverAuthError::RelativeFifoPath(fifo_file)) => {
+            Err(ParseJobserverAuthError::RelativeFifoPath(
+                String::from_utf8_lossy(&to_bytes(&fifo_file)).into_owned(),
+            ))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses the value of `--jobserver-auth=` alone, e.g. `fifo:/tmp/GMfifo`
+/// or `3,4`, the inverse of [`fmt::Display`] for [`JobServerStyle`].
+/// Like in `MAKEFLAGS` spaces in a FIFO path are escaped with a
+/// backslash.
+#[cfg(target_family = "unix")]
+impl TryFrom<&std::ffi::OsStr> for JobServerStyleOwned {
+    type Error = ParseJobserverAuthError;
+
+    fn try_from(auth: &std::ffi::OsStr) -> Result<Self, Self::Error> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let invalid =
+            || ParseJobserverAuthError::InvalidJobServerAuth(auth.to_string_lossy().into_owned());
+        let latin1: String = auth.as_bytes().iter().copied().map(char::from).collect();
+        if latin1.is_empty() || flag_value(&latin1, 0).len() != latin1.len() {
+            return Err(invalid());
+        }
+
+        if !latin1.starts_with("fifo:") {
+            let (read_fd, write_fd) = latin1.split_once(',').ok_or_else(invalid)?;
+            return match (read_fd.parse(), write_fd.parse()) {
+                (Ok(read_fd), Ok(write_fd)) => Ok(JobServerStyleOwned::Pipe(read_fd, write_fd)),
+                _ => Err(ParseJobserverAuthError::InvalidPipeDescriptors),
+            };
+        }
+        let mut makeflags = b"--jobserver-auth=".to_vec();
+        makeflags.extend_from_slice(auth.as_bytes());
+        parse_jobserver_auth_bytes(&makeflags)
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl JobServerStyleOwned {
+    /// The value of `--jobserver-auth=` advertising this job server,
+    /// the inverse of `TryFrom<&OsStr>`. Like Make, spaces and
+    /// backslashes in a FIFO path are escaped with a backslash, its
+    /// other bytes are kept as they are, also if they aren't UTF-8.
+    #[must_use]
+    pub fn to_auth(&self) -> std::ffi::OsString {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        match self {
+            JobServerStyleOwned::Fifo(fifo_file) => {
+                let mut auth = b"fifo:".to_vec();
+                for &byte in fifo_file.as_os_str().as_bytes() {
+                    if byte == b' ' || byte == b'\\' {
+                        auth.push(b'\\');
+                    }
+                    auth.push(byte);
+                }
+                std::ffi::OsString::from_vec(auth)
+            }
+            JobServerStyleOwned::Pipe(read_fd, write_fd) => format!("{read_fd},{write_fd}").into(),
+        }
+    }
+}
+
+/// Parses the value of `--jobserver-auth=` like `TryFrom<&OsStr>`.
+#[cfg(target_family = "unix")]
+impl TryFrom<&str> for JobServerStyleOwned {
+    type Error = ParseJobserverAuthError;
+
+    fn try_from(auth: &str) -> Result<Self, Self::Error> {
+        Self::try_from(std::ffi::OsStr::new(auth))
+    }
+}
+
+/// Parses the value of `--jobserver-auth=` like `TryFrom<&OsStr>`.
+#[cfg(target_family = "unix")]
+impl std::str::FromStr for JobServerStyleOwned {
+    type Err = ParseJobserverAuthError;
+
+    fn from_str(auth: &str) -> Result<Self, Self::Err> {
+        Self::try_from(auth)
+    }
+}
+
+/// The process id in the name of a FIFO created by Make, which names
+/// it `GMfifo<pid>`, e.g. 6851 for `/tmp/GMfifo6851`. It's only a
+/// heuristic for diagnostics, `None` if the name doesn't match, e.g.
+/// for a FIFO of another job server or a spoofed `MAKEFLAGS`.
+#[cfg(target_family = "unix")]
+#[must_use]
+pub fn fifo_pid(fifo_file: &std::path::Path) -> Option<u32> {
+    let pid = fifo_file.file_name()?.to_str()?.strip_prefix("GMfifo")?;
+    if !pid.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    pid.parse().ok()
+}
+
+/// The environment variable to disable the job server cooperation
+/// without editing Makefiles, set it to `1` to run serially.
+pub const JOBSERVER_DISABLE_VAR: &str = "JOBSERVER_DISABLE";
+
+/// Whether the job server is explicitly disabled with
+/// [`JOBSERVER_DISABLE_VAR`].
+#[must_use]
+pub fn jobserver_disabled() -> bool {
+    env::var(JOBSERVER_DISABLE_VAR).is_ok_and(|disable| disable == "1")
+}
+
+/// Parses the job server auth like [`parse_jobserver_auth`], but an
+/// empty or whitespace-only `MAKEFLAGS` means there's no job server,
+/// the same as if `MAKEFLAGS` wasn't set at all.
+///
+/// # Errors
+///
+/// Fails like [`parse_jobserver_auth`] for non-empty `MAKEFLAGS`.
+pub fn parse_jobserver(
+    makeflags: &str,
+) -> Result<Option<JobServerStyle<'_>>, ParseJobserverAuthError> {
+    if makeflags.trim().is_empty() {
+        return Ok(None);
+    }
+
+    parse_jobserver_auth(makeflags).map(Some)
+}
+
+/// Parses the number of parallel jobs from `-jN` or `--jobs=N` in
+/// `MAKEFLAGS`, the last one wins. A bare `-j` doesn't limit the jobs
+/// and gives `None`, like no job count at all.
+#[must_use]
+pub fn parse_job_count(makeflags: &str) -> Option<usize> {
+    flags_only(makeflags)
+        .split_whitespace()
+        .filter_map(|flag| {
+            flag.strip_prefix("--jobs=")
+                .or_else(|| flag.strip_prefix("-j"))
+        })
+        .next_back()?
+        .parse()
+        .ok()
+}
+
+/// The number of parallel jobs in `MAKEFLAGS` like
+/// [`parse_job_count`], e.g. to size a thread pool. A bare `-j` doesn't
+/// limit the jobs, the available parallelism of the machine is
+/// returned then, without any `-j` the `default`.
+#[must_use]
+pub fn parallelism_or(makeflags: &str, default: usize) -> usize {
+    let unlimited = flags_only(makeflags)
+        .split_whitespace()
+        .rfind(|flag| flag.starts_with("-j") || *flag == "--jobs" || flag.starts_with("--jobs="))
+        .is_some_and(|flag| flag == "-j" || flag == "--jobs");
+    match parse_job_count(makeflags) {
+        Some(n) => n,
+        None if unlimited => {
+            std::thread::available_parallelism().map_or(default, std::num::NonZeroUsize::get)
+        }
+        None => default,
+    }
+}
+
+/// Whether the single-letter flag `flag` is set in `MAKEFLAGS`, e.g.
+/// `n` for `--dry-run` or `k` for `--keep-going`.
+///
+/// Make puts the flags without a value into the first word as a
+/// cluster without a dash, e.g. `rR --jobserver-auth=3,4` for `-r -R`,
+/// leading spaces aside.
+/// Flags passed by hand may also come with a dash, e.g. `-k -j2`, the
+/// letters after one which takes a value, like `j`, are the value.
+#[must_use]
+pub fn has_short_flag(makeflags: &str, flag: char) -> bool {
+    // the single-letter flags which take a value
+    const WITH_VALUE: &str = "CIOWfjlo";
+
+    let words = flags_only(makeflags)
+        .split(|c: char| c.is_ascii_whitespace())
+        .filter(|word| !word.is_empty());
+    // not a variable or a bare number, which some Makes put first
+    let cluster = words
+        .clone()
+        .next()
+        .filter(|word| word.chars().all(|c| c.is_ascii_alphabetic()));
+    if cluster.is_some_and(|cluster| cluster.contains(flag)) {
+        return true;
+    }
+
+    words
+        .filter_map(|word| word.strip_prefix('-'))
+        .filter(|letters| !letters.starts_with('-'))
+        .any(|letters| {
+            let end = letters
+                .find(|c| WITH_VALUE.contains(c))
+                .map_or(letters.len(), |pos| pos + 1);
+            letters[..end].contains(flag)
+        })
+}
+
+/// The order of the goals and prerequisites with `--shuffle` of Make
+/// 4.4+.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleMode {
+    /// `--shuffle` or `--shuffle=random`, Make picks a seed.
+    Random,
+    /// `--shuffle=reverse`.
+    Reverse,
+    /// `--shuffle=SEED`, the shuffle is reproducible with the seed.
+    /// Make passes the seed it picked for `random` to sub-makes.
+    Seed(u64),
+    /// `--shuffle=none`, shuffling is off again.
+    None,
+}
+
+/// Parses `--shuffle` in `MAKEFLAGS`, the last one wins. Returns `None`
+/// if there's none or its mode is unknown.
+#[must_use]
+pub fn parse_shuffle(makeflags: &str) -> Option<ShuffleMode> {
+    let flag = flags_only(makeflags)
+        .split_whitespace()
+        .rfind(|flag| *flag == "--shuffle" || flag.starts_with("--shuffle="))?;
+
+    match flag.strip_prefix("--shuffle=") {
+        None | Some("random") => Some(ShuffleMode::Random),
+        Some("reverse") => Some(ShuffleMode::Reverse),
+        Some("none") => Some(ShuffleMode::None),
+        Some(seed) => seed.parse().ok().map(ShuffleMode::Seed),
+    }
+}
+
+/// Parses the informational job server style, e.g. `fifo` from
+/// `--jobserver-style=fifo`, which Make 4.4+ emits next to the auth.
+#[must_use]
+pub fn parse_jobserver_style_hint(makeflags: &str) -> Option<&str> {
+    let makeflags = flags_only(makeflags);
+    let pos = rfind_flag(makeflags, "--jobserver-style=")?;
+    let hint = flag_value(makeflags, pos + "--jobserver-style=".len());
+
+    if hint.is_empty() {
+        None
+    } else {
+        Some(hint)
+    }
+}
+
+/// Cross-checks the parsed job server auth against the style hint. A
+/// mismatch means `MAKEFLAGS` was most likely partially overwritten.
+#[must_use]
+pub fn style_matches_hint(style: &JobServerStyle<'_>, hint: &str) -> bool {
+    match style {
+        #[cfg(target_family = "unix")]
+        JobServerStyle::Fifo(_) => hint != "pipe" && hint != "sem",
+        JobServerStyle::Pipe(_, _) => hint != "fifo" && hint != "sem",
+        #[cfg(target_os = "windows")]
+        JobServerStyle::Sem(_) => hint != "fifo" && hint != "pipe",
+    }
+}
+
+/// Parses the job server auth from `MAKEFLAGS`, the last
+/// `--jobserver-auth=` wins. Two comma-separated numbers are the
+/// handles of an anonymous pipe, anything else is the name of a
+/// semaphore.
+///
+/// # Errors
+///
+/// Fails if there's no job server auth or the pipe handles are
+/// malformed.
+#[cfg(target_os = "windows")]
+pub fn parse_jobserver_auth(
+    makeflags: &str,
+) -> Result<JobServerStyle<'_>, ParseJobserverAuthError> {
+    let flags = flags_only(makeflags);
+    let value = [AUTH_FLAG, AUTH_FLAG_SPACED]
+        .into_iter()
+        .find_map(|flag| Some(flag_value(flags, rfind_flag(flags, flag)? + flag.len())))
+        .map(unquote)
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| ParseJobserverAuthError::InvalidJobServerAuth(makeflags.to_string()))?;
+
+    match value.split_once(',') {
+        Some((read_handle, write_handle))
+            if read_handle.starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            match (read_handle.parse(), write_handle.parse()) {
+                (Ok(read_handle), Ok(write_handle)) => {
+                    Ok(JobServerStyle::Pipe(read_handle, write_handle))
+                }
+                _ => Err(ParseJobserverAuthError::InvalidPipeDescriptors),
+            }
+        }
+        _ => Ok(JobServerStyle::Sem(value)),
+    }
+}
+
+/// Estimates the number of free tokens of the job server without
+/// consuming any. It asks the kernel how many bytes are queued in the
+/// pipe or FIFO (`FIONREAD`), which is non-destructive and doesn't
+/// block.
+///
+/// This is only an estimate, the other clients of the job server race
+/// with us and the number may already be outdated once it's returned.
+///
+/// # Errors
+///
+/// Fails if the FIFO cannot be opened or the `ioctl` fails, e.g.
+/// because the pipe file descriptors were not inherited.
+#[cfg(target_family = "unix")]
+pub fn available_token_estimate(style: &JobServerStyle<'_>) -> anyhow::Result<usize> {
+    match style {
+        JobServerStyle::Fifo(fifo_file) => {
+            let fifo = File::options()
+                .read(true)
+                .write(true)
+                .create_new(false)
+                .open(unescape_flag_value(fifo_file))
+                .with_context(|| format!("opening fifo {fifo_file}"))?;
+            queued_bytes(fifo.as_raw_fd()).context("querying queued tokens")
+        }
+        JobServerStyle::Pipe(read_fd, _) => {
+            queued_bytes(*read_fd).context("querying queued tokens")
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn queued_bytes(fd: RawFd) -> io::Result<usize> {
+    let mut queued: libc::c_int = 0;
+    // SAFETY: FIONREAD only stores the number of queued bytes in `queued`
+    if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut queued) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(usize::try_from(queued).unwrap_or(0))
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use std::{io::Write, os::fd::AsRawFd};
+
+    use crate::test_util::pipe;
+
+    #[test]
+    fn test_parse_jobserver_auth_fifo() {
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:/tmp/GMfifo6851"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:/tmp/GMfifo6851 -blah"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(
+                " -j2 --jobserver-auth=fifo:/tmp/GMfifo6852 --jobserver-auth=fifo:/tmp/GMfifo6851"
+            ),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+    }
+
+    #[test]
+    fn test_display_style() {
+        for style in [
+            super::JobServerStyle::Fifo("/tmp/GMfifo6851"),
+            super::JobServerStyle::Pipe(3, 4),
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth(&format!(" -j2 --jobserver-auth={style}")),
+                Ok(style)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_spanned() {
+        let makeflags = " -j2 --jobserver-auth=fifo:/tmp/GMfifo6851 -blah";
+        let (style, span) = super::parse_jobserver_auth_spanned(makeflags).unwrap();
+        assert_eq!(style, super::JobServerStyle::Fifo("/tmp/GMfifo6851"));
+        assert_eq!(&makeflags[span], "--jobserver-auth=fifo:/tmp/GMfifo6851");
+
+        let makeflags = "  -j3 --jobserver-auth=3,4 --jobserver-auth=-2,-2";
+        let (style, span) = super::parse_jobserver_auth_spanned(makeflags).unwrap();
+        assert_eq!(style, super::JobServerStyle::Pipe(-2, -2));
+        assert_eq!(&makeflags[span], "--jobserver-auth=-2,-2");
+
+        let makeflags = "  -j3 --jobserver-auth=3,4 -k";
+        let (_, span) = super::parse_jobserver_auth_spanned(makeflags).unwrap();
+        assert_eq!(&makeflags[span], "--jobserver-auth=3,4");
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_fifo_double_slash() {
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo://tmp/GMfifo"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:/tmp/GMfifo"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:///tmp//GMfifo -k"),
+            Ok(super::JobServerStyle::Fifo("/tmp//GMfifo")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:GMfifo"),
+            Err(super::ParseJobserverAuthError::RelativeFifoPath(
+                String::from("GMfifo")
+            )),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_relative_fifo() {
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:relative/path"),
+            Err(super::ParseJobserverAuthError::RelativeFifoPath(
+                String::from("relative/path")
+            )),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(b" --jobserver-auth=fifo:./caf\xc3\xa9"),
+            Err(super::ParseJobserverAuthError::RelativeFifoPath(
+                String::from("./café")
+            )),
+        );
+        // only the last auth counts
+        assert_eq!(
+            super::parse_jobserver_auth(" --jobserver-auth=fifo:relative --jobserver-auth=3,4"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(" --jobserver-auth=3,4 --jobserver-auth=fifo:relative"),
+            Err(super::ParseJobserverAuthError::RelativeFifoPath(
+                String::from("relative")
+            )),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_utf8() {
+        let makeflags = " -j2 --jobserver-auth=fifo:/tmp/café/GMfifo1 -é";
+        let (style, span) = super::parse_jobserver_auth_spanned(makeflags).unwrap();
+        assert_eq!(style, super::JobServerStyle::Fifo("/tmp/café/GMfifo1"));
+        assert_eq!(&makeflags[span], "--jobserver-auth=fifo:/tmp/café/GMfifo1");
+
+        assert_eq!(
+            super::parse_jobserver_auth("ü --jobserver-auth=fifo://ü --jobserver-style=füfo"),
+            Ok(super::JobServerStyle::Fifo("/ü")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth("ß -j2 --jobserver-auth=3,4 ß"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::classify_jobserver_protocol("--jobserver-auth=fifo:/tmp/café"),
+            Some(super::JobserverProtocol::Fifo)
+        );
+    }
+
+    #[test]
+    fn test_style_as_map_key() {
+        use std::collections::HashMap;
+
+        use super::{JobServerStyle, JobServerStyleOwned};
+
+        let fifo = JobServerStyle::Fifo("/tmp/GMfifo1");
+        let mut clients = HashMap::new();
+        clients.insert(fifo.clone(), 1);
+        clients.insert(JobServerStyle::Pipe(3, 4), 2);
+        assert_eq!(clients[&fifo], 1);
+        assert_eq!(clients[&JobServerStyle::Pipe(3, 4)], 2);
+        assert!(!clients.contains_key(&JobServerStyle::Pipe(4, 3)));
+
+        let owned: HashMap<_, _> = clients
+            .into_iter()
+            .map(|(style, n)| (JobServerStyleOwned::from(&style), n))
+            .collect();
+        assert_eq!(owned[&JobServerStyleOwned::Pipe(3, 4)], 2);
+    }
+
+    #[test]
+    fn test_style_try_from() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+        use super::{JobServerStyleOwned, ParseJobserverAuthError};
+
+        assert_eq!(
+            JobServerStyleOwned::try_from("3,4"),
+            Ok(JobServerStyleOwned::Pipe(3, 4))
+        );
+        assert_eq!(
+            JobServerStyleOwned::try_from(r"fifo:/tmp/a\ b/GMfifo"),
+            Ok(JobServerStyleOwned::Fifo(PathBuf::from("/tmp/a b/GMfifo")))
+        );
+        assert_eq!(
+            JobServerStyleOwned::try_from(OsStr::from_bytes(b"fifo:/tmp/\xff")),
+            Ok(JobServerStyleOwned::Fifo(PathBuf::from(OsStr::from_bytes(
+                b"/tmp/\xff"
+            ))))
+        );
+        assert_eq!(
+            "3,4".parse::<JobServerStyleOwned>(),
+            Ok(JobServerStyleOwned::Pipe(3, 4))
+        );
+
+        assert_eq!(
+            JobServerStyleOwned::try_from("fifo:"),
+            Err(ParseJobserverAuthError::EmptyFifoPath)
+        );
+        assert_eq!(
+            JobServerStyleOwned::try_from("3,x"),
+            Err(ParseJobserverAuthError::InvalidPipeDescriptors)
+        );
+        assert_eq!(
+            JobServerStyleOwned::try_from("3,4 -j2"),
+            Err(ParseJobserverAuthError::InvalidJobServerAuth(
+                "3,4 -j2".to_string()
+            ))
+        );
+        assert_eq!(
+            JobServerStyleOwned::try_from(""),
+            Err(ParseJobserverAuthError::InvalidJobServerAuth(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_style_to_auth() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+        use super::JobServerStyleOwned;
+
+        assert_eq!(JobServerStyleOwned::Pipe(3, 4).to_auth(), "3,4");
+        let style = JobServerStyleOwned::Fifo(PathBuf::from(r"/tmp/a b\c"));
+        assert_eq!(style.to_auth(), r"fifo:/tmp/a\ b\\c");
+        let style = JobServerStyleOwned::Fifo(PathBuf::from(OsStr::from_bytes(b"/tmp/\xff x")));
+        assert_eq!(style.to_auth().as_bytes(), b"fifo:/tmp/\xff\\ x");
+        for style in [
+            JobServerStyleOwned::Pipe(3, 4),
+            JobServerStyleOwned::Fifo(PathBuf::from(r"/tmp/a b\c")),
+            style,
+        ] {
+            assert_eq!(
+                JobServerStyleOwned::try_from(style.to_auth().as_os_str()),
+                Ok(style)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(
+                b" -j2 --jobserver-auth=fifo:/tmp/\xff\xfe/GMfifo1 -k"
+            ),
+            Ok(super::JobServerStyleOwned::Fifo(
+                Path::new(OsStr::from_bytes(b"/tmp/\xff\xfe/GMfifo1")).to_path_buf()
+            )),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_bytes("-j2 --jobserver-auth=fifo:/tmp/café".as_bytes()),
+            Ok(super::JobServerStyleOwned::Fifo("/tmp/café".into())),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(b"\xff --jobserver-auth=3,4"),
+            Ok(super::JobServerStyleOwned::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(b" -j2"),
+            Err(super::ParseJobserverAuthError::InvalidJobServerAuth(
+                String::from(" -j2")
+            )),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_escaped_space() {
+        let makeflags = r" -j2 --jobserver-auth=fifo:/tmp/a\ b/GMfifo -k";
+        let (style, span) = super::parse_jobserver_auth_spanned(makeflags).unwrap();
+        assert_eq!(style, super::JobServerStyle::Fifo(r"/tmp/a\ b/GMfifo"));
+        assert_eq!(&makeflags[span], r"--jobserver-auth=fifo:/tmp/a\ b/GMfifo");
+
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(makeflags.as_bytes()),
+            Ok(super::JobServerStyleOwned::Fifo("/tmp/a b/GMfifo".into())),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(br"--jobserver-auth=fifo:/tmp/a\\\ b\c"),
+            Ok(super::JobServerStyleOwned::Fifo(r"/tmp/a\ b\c".into())),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_first_flag() {
+        use super::{parse_jobserver_auth, parse_jobserver_auth_spanned, JobServerStyle};
+
+        assert_eq!(
+            parse_jobserver_auth("--jobserver-auth=3,4"),
+            Ok(JobServerStyle::Pipe(3, 4))
+        );
+        assert_eq!(
+            parse_jobserver_auth_spanned("--jobserver-auth=3,4 -j2"),
+            Ok((JobServerStyle::Pipe(3, 4), 0..20))
+        );
+        assert_eq!(
+            parse_jobserver_auth("--jobserver-auth=fifo:/tmp/GMfifo1"),
+            Ok(JobServerStyle::Fifo("/tmp/GMfifo1"))
+        );
+        assert_eq!(
+            parse_jobserver_auth("w --jobserver-auth=3,4"),
+            Ok(JobServerStyle::Pipe(3, 4))
+        );
+        assert_eq!(
+            parse_jobserver_auth("ks -j2 --jobserver-auth=-1,-1"),
+            Ok(JobServerStyle::Pipe(-1, -1))
+        );
+        // the -j after a semaphore name isn't part of the auth
+        assert_eq!(
+            parse_jobserver_auth("--jobserver-auth=gmake_semaphore -j2"),
+            Err(super::ParseJobserverAuthError::InvalidJobServerAuth(
+                "--jobserver-auth=gmake_semaphore -j2".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_goals() {
+        let makeflags = " -j2 --jobserver-auth=fifo:/tmp/x -- --jobserver-auth=fifo:/tmp/y";
+        assert_eq!(
+            super::parse_jobserver_auth(makeflags),
+            Ok(super::JobServerStyle::Fifo("/tmp/x")),
+        );
+        assert_eq!(
+            super::parse_all_jobserver_auth(makeflags),
+            vec![super::JobServerStyle::Fifo("/tmp/x")]
+        );
+        assert_eq!(super::parse_job_count(" -j2 -- -j8"), Some(2));
+        // a leading -- is an option terminator
+        assert_eq!(
+            super::parse_jobserver_auth("-- --jobserver-auth=fifo:/x"),
+            Ok(super::JobServerStyle::Fifo("/x")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(" -- --jobserver-auth=3,4 -- --jobserver-auth=5,6"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth("--jobserver-auth=3,4 -- other"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        // only a standalone -- separates
+        assert_eq!(
+            super::parse_jobserver_auth(r" --foo=-- --jobserver-auth=fifo:/tmp/a\ -- -j2"),
+            Ok(super::JobServerStyle::Fifo(r"/tmp/a\ --")),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_newline() {
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=fifo:/tmp/GMfifo1\n"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo1")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-auth=3,4\r\n-k"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        // "à" is 0xC3 0xA0 in UTF-8
+        assert_eq!(
+            super::parse_jobserver_auth_bytes(" --jobserver-auth=fifo:/tmp/à\n".as_bytes()),
+            Ok(super::JobServerStyleOwned::Fifo("/tmp/à".into())),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_spaced() {
+        assert_eq!(
+            super::parse_jobserver_auth("--jobserver-auth fifo:/tmp/GMfifo"),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth_spanned(" -j2 --jobserver-auth 3,4 -k"),
+            Ok((super::JobServerStyle::Pipe(3, 4), 5..25)),
+        );
+        // the form with `=` wins
+        assert_eq!(
+            super::parse_jobserver_auth(
+                " --jobserver-auth fifo:/tmp/GMfifo1 --jobserver-auth=fifo:/tmp/GMfifo2"
+            ),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo2")),
+        );
+
+        for makeflags in [
+            " -j2 --jobserver-auth",
+            " -j2 --jobserver-auth ",
+            "--jobserver-auth  -j2",
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth(makeflags),
+                Err(super::ParseJobserverAuthError::InvalidJobServerAuth(
+                    makeflags.to_string()
+                )),
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_quoted() {
+        for makeflags in [
+            r#" -j2 --jobserver-auth="fifo:/tmp/GMfifo""#,
+            " -j2 --jobserver-auth='fifo:/tmp/GMfifo'",
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth_spanned(makeflags),
+                Ok((super::JobServerStyle::Fifo("/tmp/GMfifo"), 5..40)),
+            );
+            assert_eq!(
+                super::parse_jobserver_auth_bytes(makeflags.as_bytes()),
+                Ok(super::JobServerStyleOwned::Fifo("/tmp/GMfifo".into())),
+            );
+        }
+        for makeflags in [
+            r#" -j2 --jobserver-auth="3,4""#,
+            " -j2 --jobserver-auth='3,4'",
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth_spanned(makeflags),
+                Ok((super::JobServerStyle::Pipe(3, 4), 5..27)),
+            );
+            assert_eq!(
+                super::classify_jobserver_protocol(makeflags),
+                Some(super::JobserverProtocol::Pipe)
+            );
+        }
+
+        // only a matching pair is stripped
+        assert_eq!(
+            super::parse_jobserver_auth(r#" --jobserver-auth="fifo:/tmp/GMfifo'"#),
+            Err(super::ParseJobserverAuthError::InvalidJobServerAuth(
+                r#" --jobserver-auth="fifo:/tmp/GMfifo'"#.to_string()
+            )),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(r#" --jobserver-auth=fifo:"/tmp/GMfifo""#),
+            Err(super::ParseJobserverAuthError::RelativeFifoPath(
+                String::from(r#""/tmp/GMfifo""#)
+            )),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_empty_fifo() {
+        assert_eq!(
+            super::parse_jobserver_auth("--jobserver-auth=fifo:"),
+            Err(super::ParseJobserverAuthError::EmptyFifoPath),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth("--jobserver-auth=fifo: -j2"),
+            Err(super::ParseJobserverAuthError::EmptyFifoPath),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_pipe() {
+        assert_eq!(
+            super::parse_jobserver_auth("  -j3 --jobserver-auth=3,3"),
+            Ok(super::JobServerStyle::Pipe(3, 3)),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth("  -j3 --jobserver-auth=3,4 --jobserver-auth=-2,-2"),
+            Ok(super::JobServerStyle::Pipe(-2, -2)),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth("  -j3 --jobserver-auth=3,4"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_malformed_pipe() {
+        for makeflags in [
+            " -j2 --jobserver-auth=3,x",
+            " -j2 --jobserver-auth=-,4",
+            " -j2 --jobserver-auth=99999999999,4",
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth(makeflags),
+                Err(super::ParseJobserverAuthError::InvalidPipeDescriptors),
+                "{makeflags}"
+            );
+            assert_eq!(
+                super::parse_jobserver_auth_bytes(makeflags.as_bytes()),
+                Err(super::ParseJobserverAuthError::InvalidPipeDescriptors),
+                "{makeflags}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_flag_boundary() {
+        assert_eq!(
+            super::parse_jobserver_auth(" -j3 --xxjobserver-auth=3,4"),
+            Err(super::ParseJobserverAuthError::InvalidJobServerAuth(
+                " -j3 --xxjobserver-auth=3,4".to_string()
+            )),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth("--jobserver-auth=3,4 --not-jobserver-auth=5,6"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::parse_all_jobserver_auth("--jobserver-auth=3,4 --x-jobserver-auth=5,6"),
+            [super::JobServerStyle::Pipe(3, 4)],
+        );
+        assert_eq!(
+            super::classify_jobserver_protocol(" --xxjobserver-auth=fifo:/tmp/x"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_jobserver_empty_makeflags() {
+        assert_eq!(super::parse_jobserver(""), Ok(None));
+        assert_eq!(super::parse_jobserver("  \t"), Ok(None));
+        assert_eq!(
+            super::parse_jobserver(" -j2 --jobserver-auth=3,4"),
+            Ok(Some(super::JobServerStyle::Pipe(3, 4)))
+        );
+        assert!(super::parse_jobserver(" -j2").is_err());
+    }
+
+    #[test]
+    fn test_parse_job_count() {
+        assert_eq!(super::parse_job_count(" -j4 --jobserver-auth=3,4"), Some(4));
+        assert_eq!(super::parse_job_count(" -j1 --jobserver-auth=3,4"), Some(1));
+        assert_eq!(super::parse_job_count("-j2 --jobs=8"), Some(8));
+        assert_eq!(super::parse_job_count(" -j --jobserver-auth=3,4"), None);
+        assert_eq!(super::parse_job_count(" --jobserver-auth=3,4"), None);
+    }
+
+    #[test]
+    fn test_fifo_pid() {
+        use std::path::Path;
+
+        assert_eq!(super::fifo_pid(Path::new("/tmp/GMfifo6851")), Some(6851));
+        for path in [
+            "/tmp/GMfifo",
+            "/tmp/GMfifo+6851",
+            "/tmp/GMfifo6851x",
+            "/tmp/jobserver-fifo",
+        ] {
+            assert_eq!(super::fifo_pid(Path::new(path)), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_parallelism_or() {
+        assert_eq!(super::parallelism_or(" -j4 --jobserver-auth=3,4", 2), 4);
+        assert_eq!(
+            super::parallelism_or(" -j --jobserver-auth=3,4", 2),
+            std::thread::available_parallelism().unwrap().get()
+        );
+        assert_eq!(
+            super::parallelism_or("--jobs", 2),
+            super::parallelism_or("-j", 2)
+        );
+        assert_eq!(super::parallelism_or(" --jobserver-auth=3,4", 2), 2);
+        assert_eq!(super::parallelism_or("", 2), 2);
+    }
+
+    #[test]
+    fn test_has_short_flag() {
+        use super::has_short_flag;
+
+        for makeflags in [
+            "rR --jobserver-auth=fifo:/tmp/x",
+            " rR --jobserver-auth=fifo:/tmp/x",
+        ] {
+            assert_eq!(
+                super::parse_jobserver_auth(makeflags),
+                Ok(super::JobServerStyle::Fifo("/tmp/x")),
+            );
+            assert!(has_short_flag(makeflags, 'r'));
+            assert!(has_short_flag(makeflags, 'R'));
+            assert!(!has_short_flag(makeflags, 'k'));
+        }
+
+        assert!(has_short_flag("ks -j2", 'k'));
+        assert!(has_short_flag("w -- VAR=x", 'w'));
+        // without flags of a single letter, the first word is empty
+        assert!(!has_short_flag(" -j2 --jobserver-auth=3,4", 's'));
+        assert!(has_short_flag(" -k -j2", 'k'));
+        assert!(has_short_flag("-nk", 'n'));
+        // the value of -O, not flags
+        assert!(!has_short_flag(" -Otarget", 'g'));
+        // a variable, not flags, or after the end of the flags
+        assert!(!has_short_flag("n=1", 'n'));
+        assert!(!has_short_flag(" -j2 -- -n", 'n'));
+    }
+
+    #[test]
+    fn test_leading_number() {
+        let makeflags = "2 --jobserver-auth=fifo:/tmp/x";
+        assert_eq!(
+            super::parse_jobserver_auth(makeflags),
+            Ok(super::JobServerStyle::Fifo("/tmp/x")),
+        );
+        assert_eq!(super::parse_job_count(makeflags), None);
+        assert!(!super::has_short_flag(makeflags, '2'));
+
+        let makeflags = "2 -j4 --jobserver-auth=3,4";
+        assert_eq!(
+            super::parse_jobserver_auth(makeflags),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(super::parse_job_count(makeflags), Some(4));
+        assert_eq!(
+            super::classify_jobserver_protocol(makeflags),
+            Some(super::JobserverProtocol::Pipe)
+        );
+    }
+
+    #[test]
+    fn test_parse_shuffle() {
+        use super::{parse_shuffle, ShuffleMode};
+
+        assert_eq!(parse_shuffle(" -j2 --shuffle"), Some(ShuffleMode::Random));
+        assert_eq!(
+            parse_shuffle(" --shuffle=random"),
+            Some(ShuffleMode::Random)
+        );
+        assert_eq!(
+            parse_shuffle(" --shuffle=reverse -j2"),
+            Some(ShuffleMode::Reverse)
+        );
+        assert_eq!(
+            parse_shuffle(" -j2 --shuffle=1681537725 --jobserver-auth=3,4"),
+            Some(ShuffleMode::Seed(1_681_537_725))
+        );
+        assert_eq!(parse_shuffle(" --shuffle=none"), Some(ShuffleMode::None));
+        // the last one wins
+        assert_eq!(
+            parse_shuffle(" --shuffle=reverse --shuffle=none"),
+            Some(ShuffleMode::None)
+        );
+        assert_eq!(parse_shuffle(" --shuffle=sideways"), None);
+        assert_eq!(parse_shuffle(" --shuffled -j2"), None);
+        assert_eq!(parse_shuffle(" -j2 -- --shuffle"), None);
+    }
+
+    #[test]
+    fn test_parse_jobserver_style_hint() {
+        assert_eq!(
+            super::parse_jobserver_style_hint(
+                " -j2 --jobserver-style=fifo --jobserver-auth=fifo:/tmp/GMfifo6851"
+            ),
+            Some("fifo"),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_style_hint(" -j2 --jobserver-auth=3,4 --jobserver-style=pipe"),
+            Some("pipe"),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_style_hint(" -j2 --jobserver-auth=3,4"),
+            None
+        );
+        assert_eq!(
+            super::parse_jobserver_style_hint(" -j2 --jobserver-style= --jobserver-auth=3,4"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_style_matches_hint() {
+        assert!(super::style_matches_hint(
+            &super::JobServerStyle::Fifo("/tmp/GMfifo6851"),
+            "fifo"
+        ));
+        assert!(super::style_matches_hint(
+            &super::JobServerStyle::Pipe(3, 4),
+            "pipe"
+        ));
+
+        // mismatches are rejected by the parser
+        assert!(!super::style_matches_hint(
+            &super::JobServerStyle::Fifo("/tmp/GMfifo6851"),
+            "pipe"
+        ));
+        assert!(!super::style_matches_hint(
+            &super::JobServerStyle::Pipe(3, 4),
+            "fifo"
+        ));
+    }
+
+    #[test]
+    fn test_parse_jobserver_auth_style_hint() {
+        assert_eq!(
+            super::parse_jobserver_auth(
+                " -j2 --jobserver-style=fifo --jobserver-auth=fifo:/tmp/GMfifo6851"
+            ),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-style=pipe --jobserver-auth=3,4"),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+
+        // the hint picks between a pipe and a fifo auth, without one
+        // the last auth wins
+        let makeflags = " -j2 --jobserver-auth=3,4 --jobserver-auth=fifo:/tmp/GMfifo6851";
+        assert_eq!(
+            super::parse_jobserver_auth(makeflags),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(&format!("{makeflags} --jobserver-style=pipe")),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        let makeflags = " -j2 --jobserver-auth=fifo:/tmp/GMfifo6851 --jobserver-auth=3,4";
+        assert_eq!(
+            super::parse_jobserver_auth(makeflags),
+            Ok(super::JobServerStyle::Pipe(3, 4)),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(&format!("{makeflags} --jobserver-style=fifo")),
+            Ok(super::JobServerStyle::Fifo("/tmp/GMfifo6851")),
+        );
+
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-style=pipe --jobserver-auth=fifo:/x"),
+            Err(super::ParseJobserverAuthError::MismatchedStyleHint(
+                String::from("pipe")
+            )),
+        );
+        assert_eq!(
+            super::parse_jobserver_auth(" -j2 --jobserver-style=fifo --jobserver-auth=3,4"),
+            Err(super::ParseJobserverAuthError::MismatchedStyleHint(
+                String::from("fifo")
+            )),
+        );
+    }
+
+    #[test]
+    fn test_classify_jobserver_protocol() {
+        use super::{classify_jobserver_protocol, JobserverProtocol};
+
+        assert_eq!(
+            classify_jobserver_protocol(" -j2 --jobserver-fds=3,4 -j"),
+            Some(JobserverProtocol::LegacyPipe)
+        );
+        assert_eq!(
+            classify_jobserver_protocol(" -j2 --jobserver-auth=3,4"),
+            Some(JobserverProtocol::Pipe)
+        );
+        assert_eq!(
+            classify_jobserver_protocol(" -j2 --jobserver-auth=fifo:/tmp/GMfifo6851"),
+            Some(JobserverProtocol::Fifo)
+        );
+        assert_eq!(
+            classify_jobserver_protocol(" -j2 --jobserver-auth=gmake_semaphore_1234"),
+            Some(JobserverProtocol::Sem)
+        );
+        // the last one wins
+        assert_eq!(
+            classify_jobserver_protocol(" --jobserver-auth=3,4 --jobserver-fds=3,4"),
+            Some(JobserverProtocol::LegacyPipe)
+        );
+        assert_eq!(classify_jobserver_protocol(" -j2"), None);
+        assert_eq!(classify_jobserver_protocol(" --jobserver-auth="), None);
+        assert_eq!(JobserverProtocol::LegacyPipe.to_string(), "legacy-pipe");
+    }
+
+    #[test]
+    fn test_parse_all_jobserver_auth() {
+        use super::{parse_all_jobserver_auth, parse_jobserver_auth, JobServerStyle};
+        use crate::test_util::capture_logs;
+
+        let makeflags =
+            " -j2 --jobserver-auth=fifo:/tmp/GMfifo1 --jobserver-auth=fifo:/tmp/GMfifo2";
+        assert_eq!(
+            parse_all_jobserver_auth(makeflags),
+            vec![
+                JobServerStyle::Fifo("/tmp/GMfifo1"),
+                JobServerStyle::Fifo("/tmp/GMfifo2")
+            ]
+        );
+        let (style, logs) = capture_logs(|| parse_jobserver_auth(makeflags));
+        assert_eq!(style, Ok(JobServerStyle::Fifo("/tmp/GMfifo2")));
+        assert!(logs.contains("more than one jobserver advertised"));
+        assert!(logs.contains("advertised=\"fifo:/tmp/GMfifo1 fifo:/tmp/GMfifo2\""));
+        assert!(logs.contains("chosen=fifo:/tmp/GMfifo2"));
+
+        // the same one twice is fine
+        let (style, logs) =
+            capture_logs(|| parse_jobserver_auth(" --jobserver-auth=3,4 --jobserver-auth=3,4"));
+        assert_eq!(style, Ok(JobServerStyle::Pipe(3, 4)));
+        assert!(logs.is_empty());
+
+        assert_eq!(
+            parse_all_jobserver_auth(" --jobserver-auth=fifo: --jobserver-auth=3,4"),
+            vec![JobServerStyle::Pipe(3, 4)]
+        );
+
+        // a malformed earlier auth is skipped, the last one is chosen
+        let makeflags = " -j2 --jobserver-auth=3,x --jobserver-auth=fifo:/tmp/x";
+        assert_eq!(
+            parse_all_jobserver_auth(makeflags),
+            vec![JobServerStyle::Fifo("/tmp/x")]
+        );
+        assert_eq!(
+            parse_jobserver_auth(makeflags),
+            Ok(JobServerStyle::Fifo("/tmp/x"))
+        );
+    }
+
+    #[test]
+    fn test_jobserver_feature() {
+        use super::{jobserver_feature, JobserverProtocol};
+
+        assert_eq!(
+            jobserver_feature(
+                "target-specific order-only second-expansion else-if shortest-stem undefine \
+                 oneshell nocomment grouped-target extra-prereqs notintermediate \
+                 shell-export archives jobserver jobserver-fifo output-sync check-symlink \
+                 guile load"
+            ),
+            Some(JobserverProtocol::Fifo)
+        );
+        assert_eq!(
+            jobserver_feature(
+                "target-specific order-only second-expansion else-if shortest-stem undefine \
+                 oneshell archives jobserver output-sync check-symlink load"
+            ),
+            Some(JobserverProtocol::Pipe)
+        );
+        assert_eq!(jobserver_feature("archives load"), None);
+        assert_eq!(jobserver_feature(""), None);
+        // not a prefix match
+        assert_eq!(jobserver_feature("jobserver-foo"), None);
+    }
+
+    #[test]
+    fn test_available_token_estimate() {
+        let (file_read, mut file_write) = pipe();
+        let fds = [file_read.as_raw_fd(), file_write.as_raw_fd()];
+
+        file_write.write_all(b"+++").unwrap();
+
+        assert_eq!(
+            super::available_token_estimate(&super::JobServerStyle::Pipe(fds[0], fds[1])).unwrap(),
+            3
+        );
+        // nothing was consumed
+        assert_eq!(
+            super::available_token_estimate(&super::JobServerStyle::Pipe(fds[0], fds[1])).unwrap(),
+            3
+        );
+    }
+}
+
+#[cfg(all(test, target_os = "windows"))]
+mod windows_tests {
+    use super::{parse_jobserver_auth, JobServerStyle, ParseJobserverAuthError};
+
+    #[test]
+    fn test_parse_jobserver_auth_pipe_or_sem() {
+        assert_eq!(
+            parse_jobserver_auth(" -j4 --jobserver-auth=284,288"),
+            Ok(JobServerStyle::Pipe(284, 288))
+        );
+        assert_eq!(
+            parse_jobserver_auth(" -j4 --jobserver-auth=gmake_semaphore_1234"),
+            Ok(JobServerStyle::Sem("gmake_semaphore_1234"))
+        );
+        assert_eq!(
+            parse_jobserver_auth(" -j4 --jobserver-auth=284,x"),
+            Err(ParseJobserverAuthError::InvalidPipeDescriptors)
+        );
+        assert_eq!(
+            parse_jobserver_auth(" -j4"),
+            Err(ParseJobserverAuthError::InvalidJobServerAuth(
+                " -j4".to_string()
+            ))
+        );
+    }
+}
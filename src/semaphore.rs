@@ -0,0 +1,92 @@
+//! The job server as a counting semaphore, for code which thinks in
+//! permits rather than tokens.
+
+use std::{io, marker::PhantomData};
+
+use crate::{Client, Token};
+
+/// A counting semaphore backed by the job server, every permit is a
+/// token. Unlike with [`Client`] a permit can't outlive the semaphore,
+/// like the permits of `tokio::sync::Semaphore`.
+#[derive(Debug)]
+pub struct JobServerSemaphore {
+    client: Client,
+}
+
+/// A permit of a [`JobServerSemaphore`], its token is released back to
+/// the job server when it's dropped.
+#[derive(Debug)]
+#[must_use = "the permit is released right away if it's not held"]
+pub struct Permit<'a> {
+    token: Token,
+    semaphore: PhantomData<&'a JobServerSemaphore>,
+}
+
+impl JobServerSemaphore {
+    /// Creates a semaphore acquiring its permits with `client`.
+    #[must_use]
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Acquires a permit, blocks until a token is available.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::acquire`].
+    pub fn acquire(&self) -> io::Result<Permit<'_>> {
+        self.client.acquire().map(|token| self.permit(token))
+    }
+
+    /// Acquires a permit if a token is available right away, never
+    /// blocks.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::try_acquire`].
+    pub fn try_acquire(&self) -> io::Result<Option<Permit<'_>>> {
+        Ok(self.client.try_acquire()?.map(|token| self.permit(token)))
+    }
+
+    #[allow(clippy::unused_self)]
+    fn permit(&self, token: Token) -> Permit<'_> {
+        Permit {
+            token,
+            semaphore: PhantomData,
+        }
+    }
+}
+
+impl Permit<'_> {
+    /// The token held by the permit.
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::fd::AsRawFd};
+
+    use super::JobServerSemaphore;
+    use crate::{available_token_estimate, test_util::pipe, Client, JobServerStyle};
+
+    #[test]
+    fn test_permit_released_on_drop() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"++").unwrap();
+        let semaphore = JobServerSemaphore::new(Client::new(&style).unwrap());
+
+        let first = semaphore.acquire().unwrap();
+        let second = semaphore.acquire().unwrap();
+        assert_eq!(second.token().as_ref(), b"+");
+        assert!(semaphore.try_acquire().unwrap().is_none());
+        assert_eq!(available_token_estimate(&style).unwrap(), 0);
+
+        drop(first);
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+        drop(second);
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+    }
+}
@@ -0,0 +1,107 @@
+//! A minimal job server, so a full acquire/release cycle can be
+//! demonstrated and tested without installing Make.
+
+use std::{
+    ffi::{CString, OsString},
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::JobServerStyleOwned;
+
+/// A FIFO job server in the style of Make 4.4.
+#[derive(Debug)]
+pub struct JobServer {
+    path: PathBuf,
+    n_tokens: usize,
+    // keeps the tokens alive, a FIFO discards its content once nobody
+    // has it open anymore
+    fifo: File,
+}
+
+impl JobServer {
+    /// Creates the FIFO at `path` and fills it with `n_tokens` tokens.
+    /// The FIFO is removed again when the job server is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the FIFO cannot be created, e.g. because `path`
+    /// already exists, or the tokens cannot be written.
+    pub fn create_fifo(path: impl AsRef<Path>, n_tokens: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: c_path is a valid NUL terminated string
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fifo = match File::options().read(true).write(true).open(&path) {
+            Ok(fifo) => fifo,
+            Err(err) => {
+                let _ = fs::remove_file(&path);
+                return Err(err);
+            }
+        };
+        let mut job_server = Self {
+            path,
+            n_tokens,
+            fifo,
+        };
+        job_server.fifo.write_all(&vec![b'+'; n_tokens])?;
+
+        Ok(job_server)
+    }
+
+    /// The path of the FIFO.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The `MAKEFLAGS` a client needs to connect to this job server.
+    /// Like Make, the parallelism accounts for the implicit token and
+    /// spaces in the path are escaped.
+    #[must_use]
+    pub fn makeflags(&self) -> OsString {
+        let mut makeflags = OsString::from(format!(" -j{} --jobserver-auth=", self.n_tokens + 1));
+        makeflags.push(JobServerStyleOwned::Fifo(self.path.clone()).to_auth());
+        makeflags
+    }
+
+    /// Sets `MAKEFLAGS` of the child process, so it connects to this
+    /// job server.
+    pub fn configure(&self, cmd: &mut Command) {
+        cmd.env("MAKEFLAGS", self.makeflags());
+    }
+}
+
+impl Drop for JobServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::JobServer;
+    use crate::{available_token_estimate, parse_jobserver_auth, JobServerStyle};
+
+    #[test]
+    fn test_create_fifo() {
+        let path = env::temp_dir().join(format!("jobserver-demo-{}-create", process::id()));
+        let job_server = JobServer::create_fifo(&path, 3).unwrap();
+
+        let makeflags = job_server.makeflags();
+        let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+        assert_eq!(style, JobServerStyle::Fifo(path.to_str().unwrap()));
+        assert_eq!(available_token_estimate(&style).unwrap(), 3);
+
+        drop(job_server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,100 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+};
+
+pub(crate) fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+// a pipe with the smallest buffer, a page on Linux, so larger writes
+// are split
+pub(crate) fn small_pipe() -> (File, File) {
+    let (read, write) = pipe();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    assert!(unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, 0) } > 0);
+    (read, write)
+}
+
+// a fifo opened read-write, a single fd usable for both directions
+pub(crate) fn bidirectional_fifo(name: &str) -> File {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-{name}", process::id()));
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+    let fifo = File::options().read(true).write(true).open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    fifo
+}
+
+#[derive(Clone, Default)]
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// runs f and returns the log events it emitted
+pub(crate) fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let writer = LogWriter::default();
+    let logs = writer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(tracing::Level::TRACE)
+        .finish();
+
+    let result = tracing::subscriber::with_default(subscriber, f);
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    (result, logs)
+}
+
+// a fifo seeded with tokens, removed again on drop
+pub(crate) struct TestFifo {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+}
+
+// creates a fifo nobody has opened yet
+pub(crate) fn mkfifo(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-{name}", process::id()));
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+    path
+}
+
+impl TestFifo {
+    pub(crate) fn new(name: &str, tokens: &[u8]) -> Self {
+        let path = mkfifo(name);
+        let mut file = File::options().read(true).write(true).open(&path).unwrap();
+        file.write_all(tokens).unwrap();
+        Self { path, file }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TestFifo {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,124 @@
+//! Rate-limiting of work by the job server, every item of an iterator
+//! is yielded together with a token.
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{Client, JobServerStyle, Token};
+
+/// Connects to the job server described by `style` and yields each
+/// item of `iter` only once a token was acquired for it. The token is
+/// held by the yielded [`Throttled`] item until it's dropped, so the
+/// items alive at once are capped by the tokens of the job server:
+///
+/// ```no_run
+/// # fn run(style: &jobserver_demo_client_rs::JobServerStyle<'_>) -> std::io::Result<()> {
+/// let jobs = vec!["a.c", "b.c", "c.c"];
+/// std::thread::scope(|scope| {
+///     for job in jobserver_demo_client_rs::throttle(style, jobs)? {
+///         let job = job?;
+///         scope.spawn(move || println!("compiling {}", *job));
+///     }
+///     Ok(())
+/// })
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// Fails like [`Client::new`].
+pub fn throttle<I: IntoIterator>(
+    style: &JobServerStyle<'_>,
+    iter: I,
+) -> io::Result<Throttle<I::IntoIter>> {
+    Ok(Throttle {
+        client: Client::new(style)?,
+        iter: iter.into_iter(),
+    })
+}
+
+/// The iterator of [`throttle`], acquiring a token blocks, an item
+/// fails if acquiring its token fails.
+#[derive(Debug)]
+pub struct Throttle<I> {
+    client: Client,
+    iter: I,
+}
+
+impl<I: Iterator> Iterator for Throttle<I> {
+    type Item = io::Result<Throttled<I::Item>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.iter.next()?;
+        Some(self.client.acquire().map(|token| Throttled { item, token }))
+    }
+}
+
+/// An item yielded by [`throttle`], its token is released when it's
+/// dropped.
+#[derive(Debug)]
+pub struct Throttled<T> {
+    item: T,
+    token: Token,
+}
+
+impl<T> Throttled<T> {
+    /// The token held for the item.
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+}
+
+impl<T> Deref for Throttled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+impl<T> DerefMut for Throttled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::throttle;
+    use crate::{test_util::TestFifo, JobServerStyle};
+
+    #[test]
+    fn test_throttle() {
+        let fifo = TestFifo::new("throttle", b"++");
+        let alive = AtomicUsize::new(0);
+        let max_alive = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for job in throttle(&JobServerStyle::Fifo(fifo.path()), 0..6).unwrap() {
+                let job = job.unwrap();
+                let n_alive = alive.fetch_add(1, Ordering::SeqCst) + 1;
+                max_alive.fetch_max(n_alive, Ordering::SeqCst);
+                let (alive, done) = (&alive, &done);
+                scope.spawn(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    done.fetch_add(*job, Ordering::SeqCst);
+                    alive.fetch_sub(1, Ordering::SeqCst);
+                    drop(job);
+                });
+            }
+        });
+
+        assert_eq!(max_alive.load(Ordering::SeqCst), 2);
+        assert_eq!(done.load(Ordering::SeqCst), 15);
+    }
+}
@@ -0,0 +1,182 @@
+//! A token acquired from the job server, a value which can be moved
+//! between threads and queues until it's released.
+
+use std::{fmt, io, sync::Arc};
+
+use crate::client::Connection;
+
+/// A token acquired from the job server with a [`Client`](crate::Client).
+///
+/// It's released back to the job server when it's dropped, or with
+/// [`Token::release`] to handle errors. It shares the connection to
+/// the job server, so it can be released from any thread, also after
+/// the client is gone. The implicit token of the serial fallback is
+/// empty.
+///
+/// Ignoring an acquired token is most likely a bug, it's released right
+/// away:
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// # fn run(client: &jobserver_demo_client_rs::Client) -> std::io::Result<()> {
+/// client.acquire()?;
+/// # Ok(())
+/// # }
+/// ```
+#[must_use = "the token is released right away if it's not held"]
+pub struct Token {
+    bytes: Vec<u8>,
+    // the id the connection tracks the outstanding token by
+    id: u64,
+    // None once released
+    connection: Option<Arc<Connection>>,
+    // released without blocking, for the tokens of try_acquire
+    nonblocking: bool,
+}
+
+impl Token {
+    pub(crate) fn new(bytes: Vec<u8>, connection: Arc<Connection>) -> Self {
+        let id = connection.on_acquire(&bytes);
+        Self {
+            bytes,
+            id,
+            connection: Some(connection),
+            nonblocking: false,
+        }
+    }
+
+    /// The bytes of the token as read from the job server, e.g. to log
+    /// or correlate tokens. Make writes `+` for every token, a token
+    /// has the size configured with
+    /// [`ClientBuilder::token_size`](crate::ClientBuilder::token_size).
+    #[must_use]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Whether the token is held from the connection.
+    pub(crate) fn is_from(&self, connection: &Arc<Connection>) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|own| Arc::ptr_eq(own, connection))
+    }
+
+    pub(crate) fn with_nonblocking_release(mut self) -> Self {
+        self.nonblocking = true;
+        self
+    }
+
+    /// Releases the token back to the job server. It consumes the
+    /// token, which is released exactly once, the drop afterwards
+    /// doesn't write it again.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to the job server fails, the token is lost
+    /// then.
+    pub fn release(mut self) -> io::Result<()> {
+        self.release_once()
+    }
+
+    fn release_once(&mut self) -> io::Result<()> {
+        match self.connection.take() {
+            // already released by Client::release_outstanding
+            Some(connection) if !connection.untrack(self.id) => Ok(()),
+            Some(connection) if self.nonblocking => connection.release_nonblocking(&self.bytes),
+            Some(connection) => connection.release(&self.bytes),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsRef<[u8]> for Token {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes()
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("bytes", &self.bytes)
+            .field("id", &self.id)
+            .field("released", &self.connection.is_none())
+            .field("nonblocking", &self.nonblocking)
+            .finish()
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if let Err(err) = self.release_once() {
+            tracing::warn!(%err, "releasing token failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::fd::AsRawFd, sync::mpsc, thread};
+
+    use crate::{available_token_estimate, test_util::pipe, Client, JobServerStyle};
+
+    #[test]
+    fn test_release_on_other_thread() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"++").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let acquirer = thread::spawn(move || {
+            sender.send(client.acquire().unwrap()).unwrap();
+            sender.send(client.acquire().unwrap()).unwrap();
+        });
+        let releaser = thread::spawn(move || {
+            let first = receiver.recv().unwrap();
+            assert_eq!(first.as_ref(), b"+");
+            first.release().unwrap();
+            // released on drop
+            drop(receiver.recv().unwrap());
+        });
+        acquirer.join().unwrap();
+        releaser.join().unwrap();
+
+        assert_eq!(available_token_estimate(&style).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"x").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        let token = client.acquire().unwrap();
+        assert_eq!(token.bytes(), [b'x']);
+        assert_eq!(token.bytes(), token.as_ref());
+        assert!(Client::serial_fallback()
+            .acquire()
+            .unwrap()
+            .bytes()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_release_once() {
+        let (file_read, mut file_write) = pipe();
+        let style = JobServerStyle::Pipe(file_read.as_raw_fd(), file_write.as_raw_fd());
+        file_write.write_all(b"+").unwrap();
+        let client = Client::new(&style).unwrap();
+
+        let mut token = client.acquire().unwrap();
+        token.release_once().unwrap();
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+        assert!(format!("{token:?}").contains("released: true"));
+        drop(token);
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+
+        client.acquire().unwrap().release().unwrap();
+        assert_eq!(available_token_estimate(&style).unwrap(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+//! An async client for build tools on tokio, blocking reads on the job
+//! server would stall the runtime.
+
+use std::{fs::File, io};
+
+use tokio::io::unix::AsyncFd;
+
+use crate::{Client, JobServerStyle, Token};
+
+/// A connection to the job server which awaits tokens on the tokio
+/// reactor instead of blocking. The tokens are read and released by a
+/// [`Client`], so they are the same [`Token`]s as of the sync client.
+#[derive(Debug)]
+pub struct AsyncClient {
+    client: Client,
+    // awaits the job server becoming readable, the tokens are read by
+    // the client, None for the serial fallback
+    readable: Option<AsyncFd<File>>,
+}
+
+impl AsyncClient {
+    /// Connects to the job server described by `style` and registers
+    /// it with the reactor, so it must be called within a tokio
+    /// runtime.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the job server cannot be opened or registered with the
+    /// reactor.
+    pub fn new(style: &JobServerStyle<'_>) -> io::Result<Self> {
+        Self::from_client(Client::new(style)?)
+    }
+
+    /// Awaits the tokens of `client`, e.g. one built with a
+    /// [`ClientBuilder`](crate::ClientBuilder) for its token size, or
+    /// the [`Client::serial_fallback`], which grants its implicit token.
+    /// It must be called within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the job server cannot be registered with the reactor.
+    pub fn from_client(client: Client) -> io::Result<Self> {
+        // only for readiness, so it needn't be non-blocking
+        let readable = client
+            .read_handle()
+            .map(|read| read.try_clone().and_then(AsyncFd::new))
+            .transpose()?;
+        Ok(Self { client, readable })
+    }
+
+    /// Acquires a token, awaits until one is available, like
+    /// [`Client::try_acquire`] whenever the job server is readable.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails, with
+    /// [`JobServerError::Closed`](crate::JobServerError::Closed) if the
+    /// job server was closed.
+    pub async fn acquire(&self) -> io::Result<Token> {
+        let Some(readable) = &self.readable else {
+            return Ok(self.client.acquire_implicit().await);
+        };
+
+        loop {
+            let mut guard = readable.readable().await?;
+            match self.client.try_acquire()? {
+                Some(token) => return Ok(token),
+                // another client was faster
+                None => guard.clear_ready(),
+            }
+        }
+    }
+
+    /// Releases a token previously acquired from this job server, like
+    /// dropping it. It doesn't block, if the pipe is full the release
+    /// is queued, see [`Client::try_acquire`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::release`].
+    pub fn release(&self, token: Token) -> io::Result<()> {
+        self.client.release(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, os::fd::AsRawFd, time::Duration};
+
+    use super::AsyncClient;
+    use crate::{
+        test_util::{pipe, TestFifo},
+        Client, ClientBuilder, JobServerError, JobServerStyle,
+    };
+
+    #[tokio::test]
+    async fn test_acquire_release() {
+        let mut fifo = TestFifo::new("tokio", b"+");
+
+        let client = AsyncClient::new(&JobServerStyle::Fifo(fifo.path())).unwrap();
+        let token = client.acquire().await.unwrap();
+        assert_eq!(token.as_ref(), b"+");
+
+        // the fifo is empty now, the next acquire has to wait
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), client.acquire())
+                .await
+                .is_err()
+        );
+
+        client.release(token).unwrap();
+        let mut token = [0; 1];
+        fifo.file.read_exact(&mut token).unwrap();
+        assert_eq!(&token, b"+");
+    }
+
+    #[tokio::test]
+    async fn test_token_size() {
+        let fifo = TestFifo::new("tokio-token-size", b"ab");
+
+        let client = ClientBuilder::new()
+            .token_size(2)
+            .build(&JobServerStyle::Fifo(fifo.path()))
+            .unwrap();
+        let client = AsyncClient::from_client(client).unwrap();
+        assert_eq!(client.acquire().await.unwrap().as_ref(), b"ab");
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        // the write end belongs to a different pipe, so the read end
+        // sees EOF once its own writer is gone
+        let (file_read, file_write) = pipe();
+        let (_other_read, other_write) = pipe();
+        let client = AsyncClient::new(&JobServerStyle::Pipe(
+            file_read.as_raw_fd(),
+            other_write.as_raw_fd(),
+        ))
+        .unwrap();
+        drop(file_write);
+
+        let err = client.acquire().await.unwrap_err();
+        assert_eq!(JobServerError::from_io(&err), Some(&JobServerError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_serial_fallback() {
+        let client = AsyncClient::from_client(Client::serial_fallback()).unwrap();
+        let token = client.acquire().await.unwrap();
+        assert!(token.as_ref().is_empty());
+
+        // the implicit token is held, the next acquire has to wait
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), client.acquire())
+                .await
+                .is_err()
+        );
+
+        drop(token);
+        let token = tokio::time::timeout(Duration::from_secs(1), client.acquire())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(token.as_ref().is_empty());
+    }
+}
@@ -0,0 +1,608 @@
+//! The token protocol on top of an exchangeable transport, so it can be
+//! tested without a real job server.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+};
+#[cfg(target_family = "unix")]
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+/// Moves token bytes from and to the job server.
+pub trait TokenTransport {
+    /// Reads a token of `width` bytes, blocks until it's complete.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from the job server fails.
+    fn read_token(&mut self, width: usize) -> io::Result<Vec<u8>>;
+
+    /// Writes the token bytes back to the job server.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to the job server fails.
+    fn write_token(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// The transport of the FIFO and the pipe style, a read and a write
+/// handle. Like Make, the FIFO is opened twice, read-only and
+/// write-only, only if that fails on Linux both refer to a single
+/// read-write open.
+/// Tokens are released from any thread, the write handle is locked, so
+/// concurrent releases don't race on its non-blocking mode.
+#[cfg(target_family = "unix")]
+#[derive(Debug)]
+pub(crate) struct FileTransport {
+    pub(crate) read: File,
+    pub(crate) write: Mutex<File>,
+    // our own non-blocking descriptions of both ends, opened on first
+    // use, None if they cannot be reopened
+    nonblocking: OnceLock<Option<(File, File)>>,
+}
+
+#[cfg(target_family = "unix")]
+impl FileTransport {
+    pub(crate) fn new(read: File, write: File) -> Self {
+        Self {
+            read,
+            write: Mutex::new(write),
+            nonblocking: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn write_fd(&self) -> RawFd {
+        self.write
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_raw_fd()
+    }
+
+    // Non-blocking is a property of the open file description, which
+    // the pipe style shares with Make and every other client. Toggling
+    // it would make their blocking reads and writes fail with
+    // WouldBlock meanwhile, so non-blocking operations use descriptions
+    // of our own, reopened via /proc. Without /proc they fall back to
+    // toggling the shared ones.
+    fn nonblocking(&self) -> Option<&(File, File)> {
+        self.nonblocking
+            .get_or_init(|| {
+                let reopened = crate::client::reopen_nonblocking(self.read.as_raw_fd(), false)
+                    .and_then(|read| {
+                        Ok((
+                            read,
+                            crate::client::reopen_nonblocking(self.write_fd(), true)?,
+                        ))
+                    });
+                match reopened {
+                    Ok(reopened) => Some(reopened),
+                    Err(err) => {
+                        tracing::warn!(
+                            %err,
+                            "cannot reopen jobserver fds, making the shared ones non-blocking"
+                        );
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    // Fills `buf` without blocking, fails with WouldBlock if nothing
+    // is available.
+    pub(crate) fn read_nonblocking(&self, buf: &mut [u8]) -> io::Result<()> {
+        if let Some((read, _)) = self.nonblocking() {
+            return read_full(read, buf);
+        }
+
+        let fd = self.read.as_raw_fd();
+        let was_nonblocking = crate::client::set_nonblocking(fd, true)?;
+        let result = read_full(&self.read, buf);
+        if !was_nonblocking {
+            crate::client::set_nonblocking(fd, false)?;
+        }
+        result
+    }
+
+    // Writes as much of `bytes` as the pipe takes right away and
+    // returns the number of bytes written, 0 if it's full.
+    pub(crate) fn write_nonblocking(&self, bytes: &[u8]) -> io::Result<usize> {
+        let nonblocking = self.nonblocking();
+        let write = self.write.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, write)) = nonblocking {
+            return write_available(write, bytes);
+        }
+
+        // the non-blocking mode is restored afterwards, like in
+        // write_token
+        let fd = write.as_raw_fd();
+        let was_nonblocking = crate::client::set_nonblocking(fd, true)?;
+        let result = write_available(&write, bytes);
+        if !was_nonblocking {
+            crate::client::set_nonblocking(fd, false)?;
+        }
+
+        result
+    }
+}
+
+// Writes `bytes` to the non-blocking `file` until it would block,
+// returns the number of bytes written.
+#[cfg(target_family = "unix")]
+fn write_available(mut file: &File, bytes: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    loop {
+        match file.write(&bytes[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) if written + n == bytes.len() => return Ok(bytes.len()),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(written),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl TokenTransport for &FileTransport {
+    fn read_token(&mut self, width: usize) -> io::Result<Vec<u8>> {
+        let mut token = vec![0; width];
+        read_full(&self.read, &mut token)?;
+        Ok(token)
+    }
+
+    // The write is always blocking, if the file description shared
+    // with Make was left non-blocking, e.g. by another client, a full
+    // pipe would fail with WouldBlock and leak the token. The
+    // non-blocking mode is restored afterwards. write_all retries short
+    // writes and EINTR, a long token may not fit into the pipe at once.
+    fn write_token(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut write = self.write.lock().unwrap_or_else(PoisonError::into_inner);
+        let fd = write.as_raw_fd();
+        let was_nonblocking = crate::client::set_nonblocking(fd, false)?;
+        let result = write.write_all(bytes);
+        if was_nonblocking {
+            crate::client::set_nonblocking(fd, true)?;
+        }
+
+        result
+    }
+}
+
+/// The transport of the pipe style on Windows, where some ports of
+/// Make pass the two Win32 `HANDLE`s of an anonymous pipe instead of a
+/// semaphore name. Tokens are read with `ReadFile` and written with
+/// `WriteFile`, as [`File`] does on Windows.
+#[cfg(target_os = "windows")]
+#[derive(Debug)]
+pub struct HandleTransport {
+    read: File,
+    write: File,
+}
+
+#[cfg(target_os = "windows")]
+impl HandleTransport {
+    /// Opens the pipe of [`JobServerStyle::Pipe`](crate::JobServerStyle::Pipe),
+    /// the handles are duplicated, so dropping the transport only
+    /// closes our copies and never the handles Make passed to us.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a handle is negative, `-1` is the pseudo-handle of the
+    /// current process, or it cannot be duplicated, e.g. because it
+    /// wasn't inherited.
+    pub fn new(read_handle: i32, write_handle: i32) -> io::Result<Self> {
+        Ok(Self {
+            read: duplicate_handle(read_handle)?,
+            write: duplicate_handle(write_handle)?,
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn duplicate_handle(handle: i32) -> io::Result<File> {
+    use std::os::windows::io::{BorrowedHandle, RawHandle};
+
+    if handle < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid jobserver handle {handle}"),
+        ));
+    }
+    // SAFETY: the handle is only borrowed for DuplicateHandle, which
+    // fails with ERROR_INVALID_HANDLE if it isn't open
+    let handle = unsafe { BorrowedHandle::borrow_raw(handle as isize as RawHandle) };
+    Ok(handle.try_clone_to_owned()?.into())
+}
+
+#[cfg(target_os = "windows")]
+impl TokenTransport for HandleTransport {
+    fn read_token(&mut self, width: usize) -> io::Result<Vec<u8>> {
+        let mut token = vec![0; width];
+        read_full(&self.read, &mut token)?;
+        Ok(token)
+    }
+
+    fn write_token(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write.write_all(bytes)
+    }
+}
+
+/// The transport of the semaphore style of native Windows Make, the
+/// auth is the name of a Win32 semaphore. A token is a count of the
+/// semaphore rather than bytes, it's handed out as Make's `+`.
+#[cfg(target_os = "windows")]
+#[derive(Debug)]
+pub struct SemaphoreTransport {
+    semaphore: std::os::windows::io::OwnedHandle,
+}
+
+#[cfg(target_os = "windows")]
+impl SemaphoreTransport {
+    /// Opens the semaphore of [`JobServerStyle::Sem`](crate::JobServerStyle::Sem).
+    /// Whether Make created it with the `Global\` prefix depends on the
+    /// Make and the session, so the name is tried as given first, then
+    /// with the prefix added, or removed if it has one.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the semaphore can be opened under neither name, the
+    /// error names both.
+    pub fn open(name: &str) -> io::Result<Self> {
+        const GLOBAL: &str = "Global\\";
+
+        let err = match open_semaphore(name) {
+            Ok(semaphore) => return Ok(Self { semaphore }),
+            Err(err) => err,
+        };
+        let other = match name.strip_prefix(GLOBAL) {
+            Some(unprefixed) => unprefixed.to_string(),
+            None => format!("{GLOBAL}{name}"),
+        };
+        open_semaphore(&other)
+            .map(|semaphore| Self { semaphore })
+            .map_err(|other_err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "cannot open jobserver semaphore {name} ({err}) nor {other} ({other_err})"
+                    ),
+                )
+            })
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use std::os::windows::io::RawHandle;
+
+    pub(super) const SYNCHRONIZE: u32 = 0x0010_0000;
+    pub(super) const SEMAPHORE_MODIFY_STATE: u32 = 0x0002;
+    pub(super) const INFINITE: u32 = u32::MAX;
+    pub(super) const WAIT_OBJECT_0: u32 = 0;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub(super) fn OpenSemaphoreW(access: u32, inherit: i32, name: *const u16) -> RawHandle;
+        pub(super) fn ReleaseSemaphore(
+            semaphore: RawHandle,
+            release_count: i32,
+            previous_count: *mut i32,
+        ) -> i32;
+        pub(super) fn WaitForSingleObject(handle: RawHandle, milliseconds: u32) -> u32;
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn open_semaphore(name: &str) -> io::Result<std::os::windows::io::OwnedHandle> {
+    use std::os::windows::io::FromRawHandle;
+
+    let wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+    // SAFETY: the name is NUL-terminated and outlives the call
+    let handle = unsafe {
+        win32::OpenSemaphoreW(
+            win32::SYNCHRONIZE | win32::SEMAPHORE_MODIFY_STATE,
+            0,
+            wide.as_ptr(),
+        )
+    };
+    if handle.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the handle was just opened and nobody else owns it
+    Ok(unsafe { std::os::windows::io::OwnedHandle::from_raw_handle(handle) })
+}
+
+#[cfg(target_os = "windows")]
+impl TokenTransport for SemaphoreTransport {
+    fn read_token(&mut self, width: usize) -> io::Result<Vec<u8>> {
+        use std::os::windows::io::AsRawHandle;
+
+        // SAFETY: only waits on the handle we own
+        match unsafe { win32::WaitForSingleObject(self.semaphore.as_raw_handle(), win32::INFINITE) }
+        {
+            win32::WAIT_OBJECT_0 => Ok(vec![b'+'; width]),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn write_token(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        use std::os::windows::io::AsRawHandle;
+
+        // SAFETY: only releases a count of the handle we own
+        if unsafe {
+            win32::ReleaseSemaphore(self.semaphore.as_raw_handle(), 1, std::ptr::null_mut())
+        } == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Fills `token`, a FIFO or a pipe may hand out a multi-byte token in
+// pieces, e.g. if a signal interrupts the read, so short reads and
+// EINTR are retried. Any other error is returned right away, also
+// WouldBlock of a non-blocking read.
+pub(crate) fn read_full(reader: impl Read, token: &mut [u8]) -> io::Result<()> {
+    read_rest(reader, token, 0)
+}
+
+// Fills the rest of `token` after the first `read` bytes, like
+// read_full.
+pub(crate) fn read_rest(
+    mut reader: impl Read,
+    token: &mut [u8],
+    mut read: usize,
+) -> io::Result<()> {
+    while read < token.len() {
+        match reader.read(&mut token[read..]) {
+            Ok(0) if read == 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    crate::JobServerError::Closed,
+                ));
+            }
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    crate::JobServerError::TruncatedToken(read, token.len()),
+                ));
+            }
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Acquires a token of `width` bytes from the transport.
+///
+/// An all-zero token is implausible, Make never writes one, it rather
+/// indicates a closed or empty read and is logged as a warning. Equal
+/// tokens in a row on the other hand are normal, Make hands out `+` for
+/// every token.
+///
+/// # Errors
+///
+/// Fails if reading from the transport fails.
+pub fn acquire_token(transport: &mut dyn TokenTransport, width: usize) -> io::Result<Vec<u8>> {
+    let token = transport.read_token(width)?;
+    if token.len() != width {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    warn_zero_token(&token);
+
+    Ok(token)
+}
+
+// Warns about an all-zero token, see acquire_token.
+pub(crate) fn warn_zero_token(token: &[u8]) {
+    if !token.is_empty() && token.iter().all(|&byte| byte == 0) {
+        tracing::warn!(
+            ?token,
+            "acquired an all-zero token, the jobserver may be corrupt"
+        );
+    }
+}
+
+/// Releases a token previously acquired from the transport, the bytes
+/// are written back verbatim.
+///
+/// # Errors
+///
+/// Fails if writing to the transport fails.
+pub fn release_token(transport: &mut dyn TokenTransport, token: &[u8]) -> io::Result<()> {
+    transport.write_token(token)
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use std::{collections::VecDeque, io};
+
+    use super::{acquire_token, read_full, release_token, TokenTransport};
+    use crate::{test_util::capture_logs, JobServerError};
+
+    #[derive(Default)]
+    struct MockTransport {
+        queued: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl TokenTransport for MockTransport {
+        fn read_token(&mut self, width: usize) -> io::Result<Vec<u8>> {
+            if self.queued.len() < width {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(self.queued.drain(..width).collect())
+        }
+
+        fn write_token(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.written.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    // Hands out one byte per read, interrupted before every byte.
+    struct BytewiseReader {
+        bytes: VecDeque<u8>,
+        interrupt: bool,
+    }
+
+    impl io::Read for BytewiseReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            match self.bytes.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_full_bytewise() {
+        let mut reader = BytewiseReader {
+            bytes: VecDeque::from(b"ABC+-".to_vec()),
+            interrupt: false,
+        };
+
+        let mut token = [0; 3];
+        read_full(&mut reader, &mut token).unwrap();
+        assert_eq!(&token, b"ABC");
+
+        let err = read_full(&mut reader, &mut token).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            JobServerError::from_io(&err),
+            Some(&JobServerError::TruncatedToken(2, 3))
+        );
+
+        let err = read_full(&mut reader, &mut token).unwrap_err();
+        assert_eq!(JobServerError::from_io(&err), Some(&JobServerError::Closed));
+    }
+
+    #[test]
+    fn test_acquire_release_round_trip() {
+        let mut transport = MockTransport {
+            queued: VecDeque::from(b"AB+".to_vec()),
+            ..MockTransport::default()
+        };
+
+        let token = acquire_token(&mut transport, 2).unwrap();
+        assert_eq!(token, b"AB");
+        release_token(&mut transport, &token).unwrap();
+        assert_eq!(transport.written, b"AB");
+
+        let token = acquire_token(&mut transport, 1).unwrap();
+        assert_eq!(token, b"+");
+        assert!(acquire_token(&mut transport, 1).is_err());
+    }
+
+    #[test]
+    fn test_acquire_zero_token_warns() {
+        let mut transport = MockTransport {
+            queued: VecDeque::from(b"+\0\0".to_vec()),
+            ..MockTransport::default()
+        };
+
+        let (token, logs) = capture_logs(|| acquire_token(&mut transport, 1));
+        assert_eq!(token.unwrap(), b"+");
+        assert!(!logs.contains("all-zero token"));
+
+        let (token, logs) = capture_logs(|| acquire_token(&mut transport, 2));
+        assert_eq!(token.unwrap(), b"\0\0");
+        assert!(logs.contains("all-zero token"));
+    }
+}
+
+#[cfg(all(test, target_os = "windows"))]
+mod windows_tests {
+    use std::{
+        io::{self, Read, Write},
+        os::windows::io::AsRawHandle,
+    };
+
+    use super::{acquire_token, release_token, HandleTransport, SemaphoreTransport};
+
+    // the handle values of an anonymous pipe, as Make would pass them
+    fn handle(handle: &impl AsRawHandle) -> i32 {
+        i32::try_from(handle.as_raw_handle() as isize).unwrap()
+    }
+
+    #[test]
+    fn test_handle_transport() {
+        let (mut reader, mut writer) = io::pipe().unwrap();
+        let mut transport = HandleTransport::new(handle(&reader), handle(&writer)).unwrap();
+
+        writer.write_all(b"+").unwrap();
+        assert_eq!(acquire_token(&mut transport, 1).unwrap(), b"+");
+        release_token(&mut transport, b"+").unwrap();
+
+        // the duplicates are closed, ours stay open
+        drop(transport);
+        writer.write_all(b"-").unwrap();
+        let mut tokens = [0; 2];
+        reader.read_exact(&mut tokens).unwrap();
+        assert_eq!(&tokens, b"+-");
+
+        assert_eq!(
+            HandleTransport::new(-1, handle(&writer))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateSemaphoreW(
+            attributes: *const std::ffi::c_void,
+            initial_count: i32,
+            maximum_count: i32,
+            name: *const u16,
+        ) -> std::os::windows::io::RawHandle;
+    }
+
+    // creates a named semaphore with one count, closed on drop
+    fn create_semaphore(name: &str) -> std::os::windows::io::OwnedHandle {
+        use std::os::windows::io::FromRawHandle;
+
+        let wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+        let handle = unsafe { CreateSemaphoreW(std::ptr::null(), 1, 1, wide.as_ptr()) };
+        assert!(!handle.is_null(), "{}", io::Error::last_os_error());
+        unsafe { std::os::windows::io::OwnedHandle::from_raw_handle(handle) }
+    }
+
+    #[test]
+    fn test_semaphore_global_prefix() {
+        let name = format!("jobserver-demo-{}-sem", std::process::id());
+        let _semaphore = create_semaphore(&format!("Global\\{name}"));
+        // outside of session 0 the unprefixed name is the session's
+        let mut transport = SemaphoreTransport::open(&name).unwrap();
+        assert_eq!(acquire_token(&mut transport, 1).unwrap(), b"+");
+        release_token(&mut transport, b"+").unwrap();
+
+        // and vice versa
+        let name = format!("jobserver-demo-{}-sem-local", std::process::id());
+        let _semaphore = create_semaphore(&name);
+        let mut transport = SemaphoreTransport::open(&format!("Global\\{name}")).unwrap();
+        assert_eq!(acquire_token(&mut transport, 1).unwrap(), b"+");
+        release_token(&mut transport, b"+").unwrap();
+
+        let err = SemaphoreTransport::open("jobserver-demo-nonexistent").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Global\\jobserver-demo-nonexistent"));
+    }
+}
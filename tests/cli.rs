@@ -0,0 +1,185 @@
+#![cfg(target_family = "unix")]
+
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    os::fd::FromRawFd,
+    process,
+    process::Command,
+};
+
+use jobserver_demo_client_rs::{Client, JobServerStyle};
+
+fn client() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"))
+}
+
+#[test]
+fn test_jobserver_disabled() {
+    let output = client()
+        .env("JOBSERVER_DISABLE", "1")
+        .env(
+            "MAKEFLAGS",
+            " -j2 --jobserver-auth=fifo:/nonexistent/GMfifo1",
+        )
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("jobserver explicitly disabled"));
+}
+
+#[test]
+fn test_jobserver_without_parallelism() {
+    // the fds aren't inherited, connecting would fail
+    let output = client()
+        .env("MAKEFLAGS", " -j1 --jobserver-auth=3,4")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("parallelism is off"));
+}
+
+#[test]
+fn test_jobserver_without_job_count() {
+    // like the library, only -j1 means serial, without -jN the job
+    // server is used, the fds aren't inherited though
+    let output = client()
+        .env("MAKEFLAGS", " --jobserver-auth=98,99")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("jobserver fd 98 is not inherited"));
+}
+
+#[test]
+fn test_format_json_pipe() {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (_file_read, mut file_write) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    file_write.write_all(b"+").unwrap();
+    // advertises the pipe in MAKEFLAGS and lets the binary inherit it
+    let job_server = Client::new(&JobServerStyle::Pipe(fds[0], fds[1])).unwrap();
+
+    let mut cmd = client();
+    job_server.configure_command(&mut cmd);
+    let output = cmd.args(["--format", "json"]).output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let token = stdout
+        .trim_end()
+        .strip_prefix(r#"{"style":"pipe","token":["#)
+        .and_then(|rest| rest.strip_suffix("]}"))
+        .unwrap_or_else(|| panic!("unexpected json {stdout:?}"));
+    let token: Vec<u8> = token.split(',').map(|byte| byte.parse().unwrap()).collect();
+    assert_eq!(token, b"+");
+
+    // the token is back
+    assert_eq!(job_server.try_acquire().unwrap().unwrap().as_ref(), b"+");
+}
+
+#[test]
+fn test_makeflags_var() {
+    // the fds aren't inherited, connecting would fail if MAKEFLAGS was
+    // read
+    let output = client()
+        .env("MAKEFLAGS", " -j2 --jobserver-auth=3,4")
+        .env("WRAPPER_FLAGS", " -j1 --jobserver-auth=3,4")
+        .args(["--makeflags-var", "WRAPPER_FLAGS"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("parallelism is off"));
+}
+
+#[test]
+fn test_malformed_makeflags_exit_code() {
+    let output = client()
+        .env("MAKEFLAGS", " -j2 --jobserver-auth=fifo:")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("parsing jobserver auth"));
+
+    let output = client()
+        .env("MAKEFLAGS", " -j2 --jobserver-auth=3,x")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid pipe descriptors"));
+}
+
+#[test]
+fn test_check_unhealthy() {
+    // the fds aren't inherited, like without the `+` prefix
+    let output = client()
+        .env("MAKEFLAGS", " -j2 --jobserver-auth=98,99")
+        .arg("--check")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("jobserver fd 98 is not inherited"));
+
+    let output = client()
+        .env_remove("MAKEFLAGS")
+        .arg("--check")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_makeflags_file_dry_run() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-makeflags", process::id()));
+    fs::write(&path, " -j4 --jobserver-auth=98,99\n").unwrap();
+    // the fds aren't inherited, they would be connected to without
+    // --dry-run
+    let output = client()
+        .env(
+            "MAKEFLAGS",
+            " -j2 --jobserver-auth=fifo:/nonexistent/GMfifo1",
+        )
+        .arg("--makeflags-file")
+        .arg(&path)
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "makeflags: set\nprotocol: pipe\nstyle: 98,99\nparallelism: 4\n"
+    );
+}
+
+#[test]
+fn test_exec_without_jobserver() {
+    let output = client()
+        .env_remove("MAKEFLAGS")
+        .args(["--exec", "--", "true"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = client()
+        .env_remove("MAKEFLAGS")
+        .args(["--exec", "--", "false"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
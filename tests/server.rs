@@ -0,0 +1,195 @@
+#![cfg(all(feature = "server", target_family = "unix"))]
+
+use std::{env, fs, process};
+
+use jobserver_demo_client_rs::{available_token_estimate, parse_jobserver_auth, server::JobServer};
+
+#[test]
+fn test_check() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-check", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.arg("--check").output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim_end(),
+        format!("jobserver usable: fifo:{}", path.display())
+    );
+
+    // no token was read
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_diagnose() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-diagnose", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.arg("--diagnose").output().unwrap();
+
+    assert!(output.status.success());
+    let report = String::from_utf8_lossy(&output.stdout);
+    for line in [
+        "makeflags: set",
+        "protocol: fifo",
+        &format!("style: fifo:{}", path.display()),
+        "parallelism: 3",
+        "open: ok",
+        "fd type: fifo",
+        "tokens available: 2",
+    ] {
+        assert!(
+            report.lines().any(|l| l == line),
+            "{line:?} missing in {report}"
+        );
+    }
+
+    // the probed tokens are back
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_client_acquires_and_returns_token() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-client", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.output().unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains('+'));
+
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_explicit_fifo() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-explicit-fifo", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    // MAKEFLAGS would fail to connect, --fifo takes precedence
+    let output = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"))
+        .env("MAKEFLAGS", " -j2 --jobserver-auth=98,99")
+        .arg("--fifo")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "+\n");
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"))
+        .env_remove("MAKEFLAGS")
+        .arg("--fifo")
+        .arg(&path)
+        .arg("--check")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim_end(),
+        format!("jobserver usable: fifo:{}", path.display())
+    );
+
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_diagnostics_on_stderr() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-stderr", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.output().unwrap();
+
+    assert!(output.status.success());
+    // only the token
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "+\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+        "jobserver present {}",
+        job_server.makeflags().to_str().unwrap()
+    )));
+}
+
+#[test]
+fn test_bench() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-bench", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.args(["--bench", "50"]).output().unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("50 tokens in "))
+        .unwrap();
+    let rate: f64 = line
+        .strip_suffix(" tokens/s")
+        .and_then(|line| line.rsplit(' ').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(rate > 0.0, "{line}");
+
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_quiet() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-quiet", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.arg("--quiet").output().unwrap();
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("jobserver present"));
+
+    let makeflags = job_server.makeflags();
+    let style = parse_jobserver_auth(makeflags.to_str().unwrap()).unwrap();
+    assert_eq!(available_token_estimate(&style).unwrap(), 2);
+}
+
+#[test]
+fn test_trace_file() {
+    let path = env::temp_dir().join(format!("jobserver-demo-{}-trace", process::id()));
+    let trace_path = env::temp_dir().join(format!("jobserver-demo-{}-trace.log", process::id()));
+    let job_server = JobServer::create_fifo(&path, 2).unwrap();
+
+    let mut cmd = process::Command::new(env!("CARGO_BIN_EXE_jobserver-demo-client-rs"));
+    job_server.configure(&mut cmd);
+    let output = cmd.arg("--trace-file").arg(&trace_path).output().unwrap();
+    let trace = fs::read_to_string(&trace_path).unwrap();
+    fs::remove_file(&trace_path).unwrap();
+
+    assert!(output.status.success());
+    let events: Vec<_> = trace
+        .lines()
+        .map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            (fields[0], fields[2])
+        })
+        .collect();
+    assert_eq!(events, [("ACQUIRE", "+"), ("RELEASE", "+")]);
+}